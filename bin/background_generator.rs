@@ -1,5 +1,6 @@
 use chrono::prelude::*;
 use image::{GenericImageView, ImageBuffer};
+use mtg::config;
 use mtg::db::{get_random_image_uris, init_conn};
 use reqwest::blocking::Client;
 use std::fs;
@@ -9,15 +10,25 @@ use std::{fs::canonicalize, thread::sleep, time::Duration};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut last_ran: i64 = 0;
-    let conn = init_conn()?;
+    let (settings, args) = config::load()?;
+    if let Some(arg) = args.first() {
+        println!(
+            "Usage: background_generator [settings]\n\n{}",
+            config::USAGE
+        );
+        if arg == "--help" || arg == "-h" {
+            return Ok(());
+        }
+        return Err(format!("Unknown argument {}", arg).into());
+    }
+    let conn = init_conn(&settings)?;
 
     // Reqwest client
     let client = Client::new();
 
     loop {
         let now = Local::now().timestamp();
-        if now - last_ran > 60 * 60 * 12 {
-            // Runs every 12 hours
+        if now - last_ran > 60 * 60 * settings.wallpaper_interval_hours as i64 {
             let image_uris = get_random_image_uris(&conn)?;
             let large = &image_uris.2;
             let full_art = &image_uris.4;
@@ -1,12 +1,21 @@
 use anyhow::Result;
 use indicatif::{ProgressBar, ProgressStyle};
+use mtg::config;
 use mtg::db::{
     init_conn, insert_cluster_assignments,
     vectors::{k_means, prep_get_all_embeddings, prep_get_vec_count, Point},
 };
 
 fn main() -> Result<()> {
-    let conn = init_conn()?;
+    let (settings, args) = config::load()?;
+    if let Some(arg) = args.first() {
+        println!("Usage: cluster_cards [settings]\n\n{}", config::USAGE);
+        if arg == "--help" || arg == "-h" {
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!("Unknown argument {}", arg));
+    }
+    let conn = init_conn(&settings)?;
     let mut count_stmt = prep_get_vec_count(&conn)?;
     let count: i64 = count_stmt.query_row([], |row| row.get(0))?;
 
@@ -37,7 +46,7 @@ fn main() -> Result<()> {
     progress_bar.finish_with_message("Card embeddings loaded");
 
     // Perform k-means clustering
-    let k = 30; // Number of clusters
+    let k = settings.clusters;
     let max_iterations = 100;
 
     println!(
@@ -0,0 +1,76 @@
+use fastembed::{EmbeddingModel, TextEmbedding};
+use hf_hub::api::sync::ApiBuilder;
+use mtg::config;
+use mtg::embedings::{parse_model, ModelManifest, MANIFEST_FILE, MODEL_FILE, TOKENIZER_FILES};
+use std::{fs, path::Path};
+
+const USAGE: &str = "Usage: fetch_model [--out <dir>]
+
+Downloads the configured embedding model into a directory that can be copied
+to hosts without internet access and loaded with the model_dir setting.
+
+Options:
+    --out <dir>  Directory to write the model to (default: the model_dir setting, or ./models/<model>)";
+
+fn parse_out_arg(args: Vec<String>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut out = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => {
+                out = Some(
+                    args.next()
+                        .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))?,
+                )
+            }
+            "--help" | "-h" => {
+                println!("{}\n\n{}", USAGE, config::USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+
+    Ok(out)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (settings, args) = config::load()?;
+    let model_name = &settings.embedding_model;
+    let out = parse_out_arg(args)?
+        .or(settings.model_dir.clone())
+        .unwrap_or_else(|| format!("./models/{}", model_name));
+    let out = Path::new(&out);
+
+    let model_info = TextEmbedding::get_model_info(&parse_model(model_name)?);
+    // Its weights are in a separate file next to the onnx graph, which
+    // can't be found when the model is loaded from memory
+    if model_info.model == EmbeddingModel::MultilingualE5Large {
+        return Err(format!("{} can't be loaded from a model directory", model_name).into());
+    }
+
+    let api = ApiBuilder::new().with_progress(true).build()?;
+    let repo = api.model(model_info.model_code.clone());
+    fs::create_dir_all(out)?;
+
+    println!("Fetching {} from {}", model_name, model_info.model_code);
+    fs::copy(repo.get(&model_info.model_file)?, out.join(MODEL_FILE))?;
+    for file in TOKENIZER_FILES {
+        fs::copy(repo.get(file)?, out.join(file))?;
+    }
+    fs::write(
+        out.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&ModelManifest {
+            model_name: model_name.clone(),
+            dim: model_info.dim,
+        })?,
+    )?;
+
+    println!(
+        "Saved {} to {}, copy it to the host and set model_dir to its path",
+        model_name,
+        out.display()
+    );
+    Ok(())
+}
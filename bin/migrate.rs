@@ -0,0 +1,83 @@
+use mtg::config;
+use mtg::db::{
+    migrations::{current_version, latest_version, migrate_to, MIGRATIONS},
+    open_conn,
+};
+
+const USAGE: &str = "Usage: migrate [--status] [--to <version>]
+
+Migrates the database schema, to the latest version by default.
+
+Options:
+    --status        List the migrations and whether they are applied
+    --to <version>  Migrate up to and including this version";
+
+enum Command {
+    Status,
+    MigrateTo(i64),
+}
+
+fn parse_args(args: Vec<String>) -> Result<Command, Box<dyn std::error::Error>> {
+    let mut command = Command::MigrateTo(latest_version());
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--status" => command = Command::Status,
+            "--to" => {
+                command = Command::MigrateTo(
+                    args.next()
+                        .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))?
+                        .parse()?,
+                )
+            }
+            "--help" | "-h" => {
+                println!("{}\n\n{}", USAGE, config::USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+
+    Ok(command)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (settings, args) = config::load()?;
+    let command = parse_args(args)?;
+    let conn = open_conn(&settings)?;
+    let current = current_version(&conn)?;
+
+    match command {
+        Command::Status => {
+            println!(
+                "Schema version {}, latest version {}",
+                current,
+                latest_version()
+            );
+            for migration in MIGRATIONS {
+                let state = if migration.version <= current {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{:>4} {:<30} {}", migration.version, migration.name, state);
+            }
+            if current > latest_version() {
+                println!("The database is newer than this binary");
+            }
+        }
+        Command::MigrateTo(target) => {
+            let applied = migrate_to(&conn, target)?;
+            for migration in &applied {
+                println!("Applied migration {} {}", migration.version, migration.name);
+            }
+            println!(
+                "Migrated from version {} to {}",
+                current,
+                current_version(&conn)?
+            );
+        }
+    }
+    Ok(())
+}
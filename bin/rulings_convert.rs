@@ -0,0 +1,146 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use mtg::config::{self, Settings};
+use mtg::db::rulings::{
+    count_rulings_without_card, count_unembedded_rulings, delete_unseen_rulings, init_seen_rulings,
+    prep_get_ruling_embedding_text_page, prep_insert_ruling, prep_insert_ruling_vec,
+    prep_insert_seen_ruling, prep_set_ruling_embedded, RulingEmbeddingText,
+};
+use mtg::db::vectors::{
+    embedding_to_bytes, prepare_embedding_index, set_embedding_index_complete, VecTable,
+};
+use mtg::scryfall::{for_each_item, Ruling};
+use rusqlite::{params, Result};
+use serde_json::Value;
+
+const USAGE: &str = "Usage: rulings_convert [--file <path>]
+
+Options:
+    --file <path>  Rulings bulk data file to read (default: the rulings_file setting)";
+
+fn parse_file_arg(
+    args: Vec<String>,
+    settings: &Settings,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = settings.rulings_file.clone();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => {
+                file = args
+                    .next()
+                    .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))?
+            }
+            "--help" | "-h" => {
+                println!("{}\n\n{}", USAGE, config::USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+
+    Ok(file)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (settings, args) = config::load()?;
+    let file = parse_file_arg(args, &settings)?;
+    let conn = mtg::db::init_conn(&settings)?;
+
+    let progress_bar = ProgressBar::new(0);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg} {bytes}/{total_bytes} ({eta})")?
+            .progress_chars("#>-"),
+    );
+    progress_bar.set_message("Processing rulings");
+    let model = mtg::embedings::init(&settings)?;
+    if prepare_embedding_index(&conn, &settings.embedding_model)? {
+        println!(
+            "Rebuilt the embedding index for {}, run scryfall_convert to embed the cards again",
+            settings.embedding_model
+        );
+    }
+
+    let mut insert_ruling = prep_insert_ruling(&conn)?;
+    init_seen_rulings(&conn)?;
+    let mut insert_seen_ruling = prep_insert_seen_ruling(&conn)?;
+
+    let mut skipped = 0;
+    let mut added = 0;
+    let on_ruling = |value: Value| -> anyhow::Result<()> {
+        let ruling: Ruling = match serde_json::from_value(value) {
+            Ok(ruling) => ruling,
+            Err(e) => {
+                progress_bar.println(format!("Skipping ruling: {}", e));
+                skipped += 1;
+                return Ok(());
+            }
+        };
+
+        let ruling_params = params![
+            ruling.oracle_id,
+            ruling.source,
+            ruling.published_at,
+            ruling.comment
+        ];
+        added += insert_ruling.execute(ruling_params)?;
+        insert_seen_ruling.execute(ruling_params)?;
+
+        Ok(())
+    };
+    for_each_item(&file, &progress_bar, on_ruling)?;
+    progress_bar.finish();
+    if skipped > 0 {
+        println!("Skipped {} malformed rulings", skipped);
+    }
+
+    let removed = delete_unseen_rulings(&conn)?;
+
+    let rulings_count = count_unembedded_rulings(&conn)?;
+    let progress_bar = ProgressBar::new(rulings_count);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg} {pos}/{len} ({eta})")?
+            .progress_chars("#>-"),
+    );
+    progress_bar.set_message("Processing embeddings");
+    let page_size = 100;
+    let mut get_ruling_text_page = prep_get_ruling_embedding_text_page(&conn)?;
+    let mut insert_ruling_vec = prep_insert_ruling_vec(&conn)?;
+    let mut set_ruling_embedded = prep_set_ruling_embedded(&conn)?;
+
+    // Rulings never change in place, so only new rulings need an embedding
+    let mut last_id = 0;
+    loop {
+        let ruling_texts: Vec<RulingEmbeddingText> = get_ruling_text_page
+            .query_map(params![last_id, page_size], RulingEmbeddingText::from_row)?
+            .collect::<Result<Vec<RulingEmbeddingText>>>()?;
+        let Some(last) = ruling_texts.last() else {
+            break;
+        };
+        last_id = last.id;
+
+        let embeddings = model.embed(
+            ruling_texts.iter().map(|r| r.text.as_str()).collect(),
+            Some(page_size),
+        )?;
+        for (ruling_text, val) in ruling_texts.iter().zip(embeddings) {
+            insert_ruling_vec.execute(params![ruling_text.id, embedding_to_bytes(&val),])?;
+            set_ruling_embedded.execute(params![ruling_text.id])?;
+        }
+        progress_bar.inc(ruling_texts.len().try_into()?);
+    }
+
+    progress_bar.finish();
+    set_embedding_index_complete(&conn, VecTable::Rulings)?;
+    println!("Added {} rulings, removed {} rulings", added, removed);
+    let waiting = count_rulings_without_card(&conn)?;
+    if waiting > 0 {
+        println!(
+            "{} rulings are not embedded until their card is imported",
+            waiting
+        );
+    }
+    Ok(())
+}
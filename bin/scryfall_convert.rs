@@ -1,86 +1,383 @@
+use fastembed::TextEmbedding;
 use indicatif::{ProgressBar, ProgressStyle};
-use mtg::db::{prep_insert_card, prep_insert_card_vec, prep_insert_image_uris, prep_insert_set};
-use rusqlite::{params, Result, Row};
+use mtg::config::{self, Settings};
+use mtg::db::{
+    checkpoints::{clear_checkpoint, get_checkpoint, save_checkpoint, source_version, Checkpoint},
+    clear_seen_cards, delete_unseen_cards,
+    fts::prep_upsert_card_fts,
+    prep_card_exists, prep_delete_card_faces, prep_delete_card_keywords,
+    prep_delete_card_legalities, prep_delete_card_relations, prep_insert_card,
+    prep_insert_card_face, prep_insert_card_keyword, prep_insert_card_legality,
+    prep_insert_card_prices, prep_insert_card_relation, prep_insert_card_vec,
+    prep_insert_image_uris, prep_insert_seen_card, prep_insert_set, set_metadata,
+    vectors::{
+        embedding_to_bytes, prep_delete_card_vec, prep_get_card_embedding_text_page,
+        prep_update_card_embedding_hash, prepare_embedding_index, set_embedding_index_complete,
+        set_embedding_text_version, CardEmbeddingText, VecTable,
+    },
+};
+use mtg::embedings::{embed_pipeline, EmbeddingBatch};
+use mtg::scryfall::{color_mask, for_each_item, parse_price, BulkType, Card};
+use rusqlite::{params, Connection, Result};
+use serde::Deserialize;
 use serde_json::Value;
-use std::{fs::File, io::Read, time::Duration};
+
+const USAGE: &str = "Usage: scryfall_convert [--bulk-type <type>] [--file <path>] [--lang <langs>]
+
+Options:
+    --bulk-type <type>  oracle_cards, unique_artwork, default_cards or all_cards (default: default_cards)
+    --file <path>       Bulk data file to read (default: ./data/scryfall-<bulk-type>.json)
+    --lang <langs>      Comma separated language codes to keep, or `all` (default: en)
+
+The file is also read from the bulk_file setting.";
+
+struct ConvertArgs {
+    bulk_type: BulkType,
+    file: String,
+    /// Empty when every language is kept
+    languages: Vec<String>,
+}
+
+impl ConvertArgs {
+    fn parse(args: Vec<String>, settings: &Settings) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut bulk_type = BulkType::DefaultCards;
+        let mut file = settings.bulk_file.clone();
+        let mut languages = vec![String::from("en")];
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--bulk-type" => bulk_type = value()?.parse()?,
+                "--file" => file = Some(value()?),
+                "--lang" => {
+                    languages = match value()?.as_str() {
+                        "all" => Vec::new(),
+                        langs => langs.split(',').map(|l| l.trim().to_string()).collect(),
+                    }
+                }
+                "--help" | "-h" => {
+                    println!("{}\n\n{}", USAGE, config::USAGE);
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+            }
+        }
+
+        Ok(ConvertArgs {
+            bulk_type,
+            file: file.unwrap_or_else(|| bulk_type.default_path()),
+            languages,
+        })
+    }
+
+    fn keeps_lang(&self, lang: Option<&str>) -> bool {
+        self.languages.is_empty() || lang.is_some_and(|l| self.languages.iter().any(|k| k == l))
+    }
+}
+
+const CARDS_STAGE: &str = "cards";
+const EMBEDDINGS_STAGE: &str = "embeddings";
+/// Cards written per transaction, and so between checkpoints
+const BATCH_SIZE: i64 = 1000;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let conn = mtg::db::init_conn()?;
-    // Read the JSON file
-    let mut file_string = String::new();
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_message("Reading scryfall data");
-    spinner.set_style(
-        ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}")?,
-    );
-    spinner.enable_steady_tick(Duration::from_millis(100));
-    File::open("./data/scryfall-default-cards.json")?.read_to_string(&mut file_string)?;
-    let cards = serde_json::from_str::<Vec<Value>>(&file_string)?;
-    let cards_count: u64 = cards.len() as u64;
-    spinner.finish();
+    let (settings, args) = config::load()?;
+    let args = ConvertArgs::parse(args, &settings)?;
+    let conn = mtg::db::init_conn(&settings)?;
+    let model = mtg::embedings::init(&settings)?;
+    if prepare_embedding_index(&conn, &settings.embedding_model)? {
+        println!(
+            "Rebuilding the embedding index for {}",
+            settings.embedding_model
+        );
+        // Cards before the checkpoint were embedded with the old model
+        if let Some(checkpoint) = get_checkpoint(&conn, EMBEDDINGS_STAGE)? {
+            save_checkpoint(
+                &conn,
+                &Checkpoint {
+                    position: 0,
+                    ..checkpoint
+                },
+            )?;
+        }
+    }
 
-    let progress_bar = ProgressBar::new(cards_count);
+    // An embeddings checkpoint means the cards were already fully ingested
+    let version = source_version(&args.file)?;
+    let checkpoint = match get_checkpoint(&conn, EMBEDDINGS_STAGE)? {
+        Some(checkpoint) if checkpoint.is_for(&args.file, &version) => {
+            println!(
+                "Resuming embeddings after card rowid {}",
+                checkpoint.position
+            );
+            checkpoint
+        }
+        _ => ingest_cards(&conn, &args, &version)?,
+    };
+    embed_cards(&conn, &settings, &model, checkpoint)?;
+
+    println!("Database created and populated successfully!");
+    Ok(())
+}
+
+/// Streams the cards from the bulk file into the db, then removes the cards
+/// that are no longer in it. Returns the checkpoint to start embedding from.
+/// `version` is the `source_version` of the bulk file.
+fn ingest_cards(
+    conn: &Connection,
+    args: &ConvertArgs,
+    version: &str,
+) -> Result<Checkpoint, Box<dyn std::error::Error>> {
+    let mut checkpoint = match get_checkpoint(conn, CARDS_STAGE)? {
+        Some(checkpoint) if checkpoint.is_for(&args.file, version) => {
+            println!("Resuming after card {} of the file", checkpoint.position);
+            checkpoint
+        }
+        _ => {
+            clear_seen_cards(conn)?;
+            Checkpoint::new(CARDS_STAGE, &args.file, version)
+        }
+    };
+    let resume_from = checkpoint.position;
+
+    let progress_bar = ProgressBar::new(0);
     progress_bar.set_style(
         ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg} {pos}/{len} ({eta})")?
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg} {bytes}/{total_bytes} ({eta})")?
             .progress_chars("#>-"),
     );
     progress_bar.set_message("Processing cards");
-    let model = mtg::embedings::init()?;
 
-    let mut insert_card = prep_insert_card(&conn)?;
-    let mut insert_card_vec = prep_insert_card_vec(&conn)?;
-    let mut insert_set = prep_insert_set(&conn)?;
-    let mut insert_image_uris = prep_insert_image_uris(&conn)?;
+    let mut insert_card = prep_insert_card(conn)?;
+    let mut insert_set = prep_insert_set(conn)?;
+    let mut insert_image_uris = prep_insert_image_uris(conn)?;
+    let mut insert_card_face = prep_insert_card_face(conn)?;
+    let mut delete_card_faces = prep_delete_card_faces(conn)?;
+    let mut upsert_card_fts = prep_upsert_card_fts(conn)?;
+    let mut insert_card_legality = prep_insert_card_legality(conn)?;
+    let mut delete_card_legalities = prep_delete_card_legalities(conn)?;
+    let mut insert_card_prices = prep_insert_card_prices(conn)?;
+    let mut insert_card_keyword = prep_insert_card_keyword(conn)?;
+    let mut delete_card_keywords = prep_delete_card_keywords(conn)?;
+    let mut insert_card_relation = prep_insert_card_relation(conn)?;
+    let mut delete_card_relations = prep_delete_card_relations(conn)?;
+    let snapshot_date = chrono::Utc::now().date_naive().to_string();
+    let mut card_exists = prep_card_exists(conn)?;
+    let mut insert_seen_card = prep_insert_seen_card(conn)?;
 
-    for card in cards {
-        // Skipping non-english to save time processing
-        if card["lang"].as_str() != Some("en") {
-            progress_bar.inc(1);
-            continue;
+    let mut skipped = 0;
+    // Saves a card and everything hanging off of it. Returns whether the card is new.
+    let mut save_card = |value: Value| -> anyhow::Result<bool> {
+        // Skipping unwanted languages to save time processing
+        if !args.keeps_lang(value["lang"].as_str()) {
+            return Ok(false);
+        }
+        // Malformed cards count as seen so a bad record doesn't remove the last good copy
+        if let Some(id) = value["id"].as_str() {
+            insert_seen_card.execute(params![id])?;
         }
 
+        let card = match Card::deserialize(&value) {
+            Ok(card) => card,
+            Err(e) => {
+                progress_bar.println(format!(
+                    "Skipping card {}: {}",
+                    value["id"].as_str().unwrap_or("<missing id>"),
+                    e
+                ));
+                skipped += 1;
+                return Ok(false);
+            }
+        };
+
+        let is_new = !card_exists.query_row(params![card.id], |row| row.get::<_, bool>(0))?;
         let _set_res_id = insert_set.insert(params![
-            card["set"].as_str(),
-            card["set_name"].as_str(),
-            card["set_type"].as_str(),
-            card["released_at"].as_str()
+            card.set,
+            card.set_name,
+            card.set_type,
+            card.released_at
         ])?;
         let _res_id = insert_card.insert(params![
-            card["id"].as_str(),
-            card["oracle_id"].as_str(),
-            card["name"].as_str(),
-            card["lang"].as_str(),
-            card["released_at"].as_str(),
-            card["mana_cost"].as_str(),
-            card["cmc"].as_f64(),
-            card["type_line"].as_str(),
-            card["oracle_text"].as_str(),
-            card["power"].as_str(),
-            card["toughness"].as_str(),
-            card["rarity"].as_str(),
-            card["flavor_text"].as_str(),
-            card["artist"].as_str(),
-            card["set"].as_str(),
-            card["collector_number"].to_string(),
-            card["digital"].to_string(),
+            card.id,
+            card.oracle_id,
+            card.name,
+            card.lang,
+            card.released_at,
+            card.mana_cost,
+            card.cmc,
+            card.type_line,
+            card.oracle_text,
+            card.power,
+            card.toughness,
+            card.rarity,
+            card.flavor_text,
+            card.artist,
+            card.set,
+            card.collector_number,
+            card.digital,
+            card.all_colors().map(|c| color_mask(&c)),
+            color_mask(&card.color_identity),
+            card.produced_mana.as_ref().map(|c| color_mask(c)),
         ])?;
-        if let Some(image_uris) = card["image_uris"].as_object() {
+        if let Some(image_uris) = &card.image_uris {
             let _image_res_id = insert_image_uris.insert(params![
-                card["id"].as_str(),
-                image_uris["small"].as_str(),
-                image_uris["normal"].as_str(),
-                image_uris["large"].as_str(),
-                image_uris["png"].as_str(),
-                image_uris["art_crop"].as_str(),
-                image_uris["border_crop"].as_str(),
+                card.id,
+                image_uris.small,
+                image_uris.normal,
+                image_uris.large,
+                image_uris.png,
+                image_uris.art_crop,
+                image_uris.border_crop,
             ])?;
         }
 
-        progress_bar.inc(1);
-    }
+        delete_card_faces.execute(params![card.id])?;
+        for (face_index, face) in card.card_faces.iter().flatten().enumerate() {
+            let image_uris = face.image_uris.as_ref();
+            insert_card_face.execute(params![
+                card.id,
+                face_index,
+                face.name,
+                face.mana_cost,
+                face.type_line,
+                face.oracle_text,
+                face.power,
+                face.toughness,
+                face.flavor_text,
+                face.artist,
+                image_uris.map(|i| &i.small),
+                image_uris.map(|i| &i.normal),
+                image_uris.map(|i| &i.large),
+                image_uris.map(|i| &i.png),
+                image_uris.map(|i| &i.art_crop),
+                image_uris.map(|i| &i.border_crop),
+            ])?;
+        }
+        upsert_card_fts.execute(params![card.id])?;
+
+        delete_card_legalities.execute(params![card.id])?;
+        for (format, legality) in &card.legalities.0 {
+            insert_card_legality.execute(params![card.id, format, legality.as_str()])?;
+        }
+
+        delete_card_keywords.execute(params![card.id])?;
+        for keyword in &card.keywords {
+            insert_card_keyword.execute(params![card.id, keyword])?;
+        }
+
+        // all_parts lists the card itself too
+        delete_card_relations.execute(params![card.id])?;
+        for part in card.all_parts.iter().flatten().filter(|p| p.id != card.id) {
+            insert_card_relation.execute(params![
+                card.id,
+                part.id,
+                part.component,
+                part.name,
+                part.type_line
+            ])?;
+        }
+
+        if !card.prices.is_empty() {
+            insert_card_prices.execute(params![
+                card.id,
+                snapshot_date,
+                parse_price(&card.prices.usd),
+                parse_price(&card.prices.usd_foil),
+                parse_price(&card.prices.usd_etched),
+                parse_price(&card.prices.eur),
+                parse_price(&card.prices.tix),
+            ])?;
+        }
+
+        Ok(is_new)
+    };
+
+    let mut position = 0;
+    conn.execute_batch("BEGIN;")?;
+    let on_card = |value: Value| -> anyhow::Result<()> {
+        position += 1;
+        // Cards before the checkpoint are already saved, but still have to be read
+        if position <= resume_from {
+            return Ok(());
+        }
+
+        if save_card(value)? {
+            checkpoint.added += 1;
+        }
+        checkpoint.position = position;
+        if position % BATCH_SIZE == 0 {
+            save_checkpoint(conn, &checkpoint)?;
+            conn.execute_batch("COMMIT; BEGIN;")?;
+        }
+
+        Ok(())
+    };
+    for_each_item(&args.file, &progress_bar, on_card)?;
+    save_checkpoint(conn, &checkpoint)?;
+    conn.execute_batch("COMMIT;")?;
     progress_bar.finish();
+    if skipped > 0 {
+        println!("Skipped {} malformed cards", skipped);
+    }
 
+    // Finishing the cards stage and starting the embeddings stage happen together
+    conn.execute_batch("BEGIN;")?;
+    let removed = delete_unseen_cards(conn)?;
+    set_metadata(conn, "bulk_type", args.bulk_type.as_str())?;
+    set_metadata(conn, "source_file", &args.file)?;
+    let languages = if args.languages.is_empty() {
+        String::from("all")
+    } else {
+        args.languages.join(",")
+    };
+    set_metadata(conn, "languages", &languages)?;
+    set_metadata(conn, "ingested_at", &chrono::Utc::now().to_rfc3339())?;
+
+    let embeddings_checkpoint = Checkpoint {
+        added: checkpoint.added,
+        removed: removed as i64,
+        ..Checkpoint::new(EMBEDDINGS_STAGE, &args.file, version)
+    };
+    save_checkpoint(conn, &embeddings_checkpoint)?;
+    clear_checkpoint(conn, CARDS_STAGE)?;
+    conn.execute_batch("COMMIT;")?;
+
+    Ok(embeddings_checkpoint)
+}
+
+/// A page of cards read for embedding
+struct CardTextPage {
+    /// The cards of the page whose embedding text changed
+    changed: Vec<CardEmbeddingText>,
+    /// Every card up to this rowid has been looked at once the page is written
+    last_rowid: i64,
+    scanned: usize,
+}
+
+impl EmbeddingBatch for CardTextPage {
+    fn texts(&self) -> Vec<&str> {
+        self.changed.iter().map(|c| c.text.as_str()).collect()
+    }
+}
+
+/// Embeds every card, after the checkpoint, whose embedding text changed.
+/// Pages of cards are read on their own connection, embedded on another
+/// thread and written back, in order, on `conn`.
+fn embed_cards(
+    conn: &Connection,
+    settings: &Settings,
+    model: &TextEmbedding,
+    mut checkpoint: Checkpoint,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cards_count: u64 = conn.query_row(
+        "SELECT COUNT(*) FROM cards WHERE rowid > ?;",
+        params![checkpoint.position],
+        |row| row.get(0),
+    )?;
     let progress_bar = ProgressBar::new(cards_count);
     progress_bar.set_style(
         ProgressStyle::default_bar()
@@ -89,68 +386,66 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
     progress_bar.set_message("Processing embeddings");
     let page_size = 100;
-    let mut get_card_info_page = conn.prepare(&format!(
-        "SELECT 
-            name, 
-            COALESCE(oracle_text, ''),
-            COALESCE(flavor_text, ''),
-            COALESCE(power, ''),
-            COALESCE(toughness, ''),
-            COALESCE(type_line, ''),
-            COALESCE(mana_cost, ''),
-            rowid
-        FROM cards c
-        WHERE rowid >= ?
-        LIMIT {};",
-        page_size
-    ))?;
-
-    let card_info_mapper = |f: &Row| {
-        let name: String = f.get(0)?;
-        let oracle: String = f.get(1)?;
-        let flavor: String = f.get(2)?;
-        let power: String = f.get(3)?;
-        let toughtness: String = f.get(4)?;
-        let mana_cost: String = f.get(5)?;
-        let type_line: String = f.get(6)?;
-
-        Ok(format!(
-            "<name>{:?}<power>{:?}<toughness>{:?}<cost>{:?}<type>{:?}<oracle>{:?}<flavor>{:?}",
-            &name, &power, &toughtness, &mana_cost, &type_line, &oracle, &flavor,
-        ))
+
+    let reader_conn = mtg::db::init_conn(settings)?;
+    let mut last_read_rowid = checkpoint.position;
+    let read_page = move || -> anyhow::Result<Option<CardTextPage>> {
+        let mut get_card_text_page = prep_get_card_embedding_text_page(&reader_conn)?;
+        let card_texts: Vec<CardEmbeddingText> = get_card_text_page
+            .query_map(
+                params![last_read_rowid, page_size],
+                CardEmbeddingText::from_row,
+            )?
+            .collect::<Result<Vec<CardEmbeddingText>>>()?;
+        let Some(last) = card_texts.last() else {
+            return Ok(None);
+        };
+        last_read_rowid = last.rowid;
+
+        Ok(Some(CardTextPage {
+            last_rowid: last.rowid,
+            scanned: card_texts.len(),
+            changed: card_texts
+                .into_iter()
+                .filter(|c| c.needs_embedding())
+                .collect(),
+        }))
     };
 
-    // Loop through the newly stored data, and process the vector embeddings
-    let mut offset = 0;
-    loop {
-        let card_info: Vec<String> = get_card_info_page
-            .query_map(params![offset], card_info_mapper)?
-            .map(|result| result.map_err(|e| e.into()))
-            .collect::<Result<Vec<String>, Box<dyn std::error::Error>>>()?;
-
-        if card_info.len() > 0 {
-            let embeddings = model.embed(card_info.clone(), Some(page_size))?;
-            for (idx, val) in embeddings.iter().enumerate() {
-                insert_card_vec.execute(params![
-                    idx + offset,
-                    val.iter()
-                        .flat_map(|f| f.to_ne_bytes().to_vec())
-                        .collect::<Vec<_>>(),
-                ])?;
-            }
+    let mut insert_card_vec = prep_insert_card_vec(conn)?;
+    let mut delete_card_vec = prep_delete_card_vec(conn)?;
+    let mut update_embedding_hash = prep_update_card_embedding_hash(conn)?;
+    let write_page = |page: CardTextPage, embeddings: Vec<Vec<f32>>| -> anyhow::Result<()> {
+        conn.execute_batch("BEGIN;")?;
+        for (card_text, val) in page.changed.iter().zip(embeddings) {
+            delete_card_vec.execute(params![card_text.rowid])?;
+            insert_card_vec.execute(params![card_text.rowid, embedding_to_bytes(&val),])?;
+            update_embedding_hash.execute(params![card_text.text_hash(), card_text.rowid])?;
         }
+        // Cards without a previous hash are new, or were never embedded
+        checkpoint.changed += page
+            .changed
+            .iter()
+            .filter(|c| c.embedding_hash.is_some())
+            .count() as i64;
+        checkpoint.position = page.last_rowid;
+        save_checkpoint(conn, &checkpoint)?;
+        conn.execute_batch("COMMIT;")?;
 
-        if card_info.len() < page_size {
-            progress_bar.finish();
-            break;
-        }
+        progress_bar.inc(page.scanned.try_into()?);
+        Ok(())
+    };
 
-        progress_bar.inc(card_info.len().try_into()?);
-        offset += page_size
-    }
+    embed_pipeline(model, read_page, write_page)?;
 
-    // Step 9: Finish and clear the progress bar
     progress_bar.finish();
-    println!("Database created and populated successfully!");
+    set_embedding_text_version(conn)?;
+    // Written last, so an interrupted run leaves the index refused by searches
+    set_embedding_index_complete(conn, VecTable::Cards)?;
+    clear_checkpoint(conn, EMBEDDINGS_STAGE)?;
+    println!(
+        "Added {} cards, changed {} cards, removed {} cards",
+        checkpoint.added, checkpoint.changed, checkpoint.removed
+    );
     Ok(())
 }
@@ -0,0 +1,63 @@
+use mtg::config::{self, Settings};
+use mtg::db::prep_insert_set_metadata;
+use mtg::scryfall::SetList;
+use rusqlite::params;
+use std::{fs::File, io::BufReader};
+
+const USAGE: &str = "Usage: sets_convert [--file <path>]
+
+Options:
+    --file <path>  Scryfall sets data to read (default: the sets_file setting)";
+
+fn parse_file_arg(
+    args: Vec<String>,
+    settings: &Settings,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = settings.sets_file.clone();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--file" => {
+                file = args
+                    .next()
+                    .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))?
+            }
+            "--help" | "-h" => {
+                println!("{}\n\n{}", USAGE, config::USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+        }
+    }
+
+    Ok(file)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (settings, args) = config::load()?;
+    let file = parse_file_arg(args, &settings)?;
+    let conn = mtg::db::init_conn(&settings)?;
+
+    // The sets data is small enough to read in one go
+    let sets =
+        serde_json::from_reader::<_, SetList>(BufReader::new(File::open(&file)?))?.into_sets();
+
+    let mut insert_set = prep_insert_set_metadata(&conn)?;
+    for set in &sets {
+        insert_set.execute(params![
+            set.code,
+            set.name,
+            set.set_type,
+            set.released_at,
+            set.parent_set_code,
+            set.block,
+            set.card_count,
+            set.digital,
+            set.icon_svg_uri,
+        ])?;
+    }
+
+    println!("Saved {} sets", sets.len());
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use mtg::config;
+use mtg::db::{
+    init_conn, prep_insert_card_vec,
+    vectors::{
+        check_embedding_model, count_orphan_card_vecs, delete_orphan_card_vecs, embedding_to_bytes,
+        euclidean_distance, get_card_embedding, prep_delete_card_vec,
+        prep_get_card_embedding_text_missing, prep_get_card_embedding_text_sample,
+        prep_update_card_embedding_hash, CardEmbeddingText,
+    },
+};
+use rusqlite::{params, Connection, Result};
+
+const USAGE: &str = "Usage: verify_embeddings [--sample <n>] [--tolerance <distance>] [--repair]
+
+Samples cards, re-embeds them and compares the result with the vector stored
+under the card's rowid in card_vecs.
+
+Options:
+    --sample <n>            Cards to re-embed (default: 100)
+    --tolerance <distance>  Largest euclidean distance counted as a match (default: 0.001)
+    --repair                Re-embed mismatched and missing cards, and delete orphaned vectors";
+
+struct VerifyArgs {
+    sample: u32,
+    tolerance: f32,
+    repair: bool,
+}
+
+impl VerifyArgs {
+    fn parse(args: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut verify_args = VerifyArgs {
+            sample: 100,
+            tolerance: 0.001,
+            repair: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or(format!("Missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--sample" => verify_args.sample = value()?.parse()?,
+                "--tolerance" => verify_args.tolerance = value()?.parse()?,
+                "--repair" => verify_args.repair = true,
+                "--help" | "-h" => {
+                    println!("{}\n\n{}", USAGE, config::USAGE);
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE).into()),
+            }
+        }
+
+        Ok(verify_args)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (settings, args) = config::load()?;
+    let args = VerifyArgs::parse(args)?;
+    let conn = init_conn(&settings)?;
+    // A partly embedded index is what --repair fills in
+    check_embedding_model(&conn, &settings.embedding_model)?;
+    let model = mtg::embedings::init(&settings)?;
+
+    let sample: Vec<CardEmbeddingText> = prep_get_card_embedding_text_sample(&conn)?
+        .query_map(params![args.sample], CardEmbeddingText::from_row)?
+        .collect::<Result<Vec<CardEmbeddingText>>>()?;
+
+    let progress_bar = ProgressBar::new(sample.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg} {pos}/{len} ({eta})")?
+            .progress_chars("#>-"),
+    );
+    progress_bar.set_message("Verifying embeddings");
+
+    let mut mismatched = Vec::new();
+    let mut missing = 0;
+    for chunk in sample.chunks(100) {
+        let embeddings = model.embed(
+            chunk.iter().map(|c| c.text.as_str()).collect(),
+            Some(chunk.len()),
+        )?;
+        for (card_text, expected) in chunk.iter().zip(embeddings) {
+            match get_card_embedding(&conn, card_text.rowid)? {
+                Some(stored) => {
+                    let distance = euclidean_distance(&stored, &expected);
+                    if stored.len() != expected.len() || distance > args.tolerance {
+                        progress_bar.println(format!(
+                            "Mismatch for card rowid {} (distance {})",
+                            card_text.rowid, distance
+                        ));
+                        mismatched.push((card_text, expected));
+                    }
+                }
+                None => {
+                    progress_bar
+                        .println(format!("Missing vector for card rowid {}", card_text.rowid));
+                    missing += 1;
+                }
+            }
+        }
+        progress_bar.inc(chunk.len() as u64);
+    }
+    progress_bar.finish();
+
+    let orphans = count_orphan_card_vecs(&conn)?;
+    println!(
+        "Checked {} cards: {} mismatched, {} missing a vector. {} vectors have no card.",
+        sample.len(),
+        mismatched.len(),
+        missing,
+        orphans
+    );
+
+    if !args.repair {
+        if !mismatched.is_empty() || missing > 0 || orphans > 0 {
+            println!("Run with --repair to fix them");
+        }
+        return Ok(());
+    }
+
+    conn.execute_batch("BEGIN;")?;
+    for (card_text, embedding) in &mismatched {
+        write_card_vec(&conn, card_text, embedding)?;
+    }
+    let deleted_orphans = delete_orphan_card_vecs(&conn)?;
+    conn.execute_batch("COMMIT;")?;
+
+    // A sample missing vectors means others probably are too, so fill in all of them
+    let missing_cards: Vec<CardEmbeddingText> = prep_get_card_embedding_text_missing(&conn)?
+        .query_map([], CardEmbeddingText::from_row)?
+        .collect::<Result<Vec<CardEmbeddingText>>>()?;
+    for chunk in missing_cards.chunks(100) {
+        let embeddings = model.embed(
+            chunk.iter().map(|c| c.text.as_str()).collect(),
+            Some(chunk.len()),
+        )?;
+        conn.execute_batch("BEGIN;")?;
+        for (card_text, embedding) in chunk.iter().zip(embeddings) {
+            write_card_vec(&conn, card_text, &embedding)?;
+        }
+        conn.execute_batch("COMMIT;")?;
+    }
+
+    println!(
+        "Repaired {} mismatched vectors, added {} missing vectors and deleted {} orphaned vectors",
+        mismatched.len(),
+        missing_cards.len(),
+        deleted_orphans
+    );
+    Ok(())
+}
+
+/// Replaces the vector stored under the card's rowid
+fn write_card_vec(
+    conn: &Connection,
+    card_text: &CardEmbeddingText,
+    embedding: &[f32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    prep_delete_card_vec(conn)?.execute(params![card_text.rowid])?;
+    prep_insert_card_vec(conn)?.execute(params![card_text.rowid, embedding_to_bytes(embedding)])?;
+    prep_update_card_embedding_hash(conn)?
+        .execute(params![card_text.text_hash(), card_text.rowid])?;
+    Ok(())
+}
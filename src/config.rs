@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::embedings::DEFAULT_MODEL;
+
+/// The config file read when neither `--config` nor `MTG_CONFIG` is given.
+/// It is optional, the defaults are used when it does not exist.
+pub const DEFAULT_CONFIG_PATH: &str = "./mtg.toml";
+
+/// Settings every binary accepts, appended to their own usage text
+pub const USAGE: &str = "Settings (also read from MTG_<NAME> env vars and the [settings] of a TOML file):
+    --config <path>                  TOML file to read settings from (default: ./mtg.toml)
+    --db-path <path>                 Sqlite database (default: ./data/scryfall_cards.db)
+    --bind-addr <addr>               Address the server listens on (default: 0.0.0.0:3000)
+    --static-dir <path>              Directory served as the frontend (default: www)
+    --clusters <k>                   Clusters cluster_cards groups the cards into (default: 30)
+    --wallpaper-interval-hours <n>   Hours between new wallpapers (default: 12)
+    --bulk-file <path>               Scryfall card bulk data (default: ./data/scryfall-<bulk-type>.json)
+    --rulings-file <path>            Scryfall rulings bulk data (default: ./data/scryfall-rulings.json)
+    --sets-file <path>               Scryfall sets data (default: ./data/scryfall-sets.json)
+    --embedding-model <name>         Model cards and rulings are embedded with (default: bge-small-en-v1.5)
+    --model-dir <path>               Load the model from a fetch_model directory instead of downloading it";
+
+/// Settings shared by the server and the binaries. Each source overrides the
+/// one before it: the defaults, the config file, `MTG_*` environment
+/// variables, then command line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub db_path: String,
+    pub bind_addr: String,
+    pub static_dir: String,
+    pub clusters: usize,
+    pub wallpaper_interval_hours: u64,
+    /// Defaults to the path of the chosen bulk type when not set
+    pub bulk_file: Option<String>,
+    pub rulings_file: String,
+    pub sets_file: String,
+    /// One of the names in `embedings::parse_model`
+    pub embedding_model: String,
+    /// A directory written by `fetch_model`, for hosts without internet access
+    pub model_dir: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            db_path: String::from("./data/scryfall_cards.db"),
+            bind_addr: String::from("0.0.0.0:3000"),
+            static_dir: String::from("www"),
+            clusters: 30,
+            wallpaper_interval_hours: 12,
+            bulk_file: None,
+            rulings_file: String::from("./data/scryfall-rulings.json"),
+            sets_file: String::from("./data/scryfall-sets.json"),
+            embedding_model: String::from(DEFAULT_MODEL),
+            model_dir: None,
+        }
+    }
+}
+
+/// The keys of [`Settings`], named like the TOML file. The env var is the
+/// upper cased key prefixed with `MTG_`, the flag is the key in kebab case.
+const KEYS: [&str; 10] = [
+    "db_path",
+    "bind_addr",
+    "static_dir",
+    "clusters",
+    "wallpaper_interval_hours",
+    "bulk_file",
+    "rulings_file",
+    "sets_file",
+    "embedding_model",
+    "model_dir",
+];
+
+/// The config file nests the settings in a table so it can grow other sections
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    settings: Settings,
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "db_path" => self.db_path = value.to_string(),
+            "bind_addr" => self.bind_addr = value.to_string(),
+            "static_dir" => self.static_dir = value.to_string(),
+            "clusters" => self.clusters = value.parse()?,
+            "wallpaper_interval_hours" => self.wallpaper_interval_hours = value.parse()?,
+            "bulk_file" => self.bulk_file = Some(value.to_string()),
+            "rulings_file" => self.rulings_file = value.to_string(),
+            "sets_file" => self.sets_file = value.to_string(),
+            "embedding_model" => self.embedding_model = value.to_string(),
+            "model_dir" => self.model_dir = Some(value.to_string()),
+            _ => return Err(anyhow!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Checks the resolved settings, wherever each one came from
+    fn validate(&self) -> Result<()> {
+        if self.clusters == 0 {
+            return Err(anyhow!("clusters must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading config {}", path))?;
+        let file: ConfigFile =
+            toml::from_str(&contents).with_context(|| format!("Parsing config {}", path))?;
+        Ok(file.settings)
+    }
+}
+
+fn env_var(key: &str) -> String {
+    format!("MTG_{}", key.to_uppercase())
+}
+
+fn flag(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+/// Resolves the settings from the process's environment and arguments
+pub fn load() -> Result<(Settings, Vec<String>)> {
+    load_from(std::env::args().skip(1).collect())
+}
+
+/// Resolves the settings, returning the arguments that are not settings flags
+/// for the binary to parse itself
+pub fn load_from(args: Vec<String>) -> Result<(Settings, Vec<String>)> {
+    // The config file has to be read before the flags that override it
+    let config_path = match args.iter().position(|a| a == "--config") {
+        Some(i) => Some(
+            args.get(i + 1)
+                .cloned()
+                .ok_or(anyhow!("Missing value for --config"))?,
+        ),
+        None => std::env::var(env_var("config")).ok(),
+    };
+    let mut settings = match config_path {
+        Some(path) => Settings::from_file(&path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            Settings::from_file(DEFAULT_CONFIG_PATH)?
+        }
+        None => Settings::default(),
+    };
+
+    for key in KEYS {
+        if let Ok(value) = std::env::var(env_var(key)) {
+            settings
+                .set(key, &value)
+                .with_context(|| format!("Invalid {}", env_var(key)))?;
+        }
+    }
+
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            args.next();
+            continue;
+        }
+        match KEYS.iter().find(|key| flag(key) == arg) {
+            Some(key) => {
+                let value = args.next().ok_or(anyhow!("Missing value for {}", arg))?;
+                settings
+                    .set(key, &value)
+                    .with_context(|| format!("Invalid {}", arg))?;
+            }
+            None => rest.push(arg),
+        }
+    }
+
+    settings.validate()?;
+    Ok((settings, rest))
+}
@@ -0,0 +1,98 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::{fs, time::UNIX_EPOCH};
+
+/// Progress of an ingest stage, saved in the same transaction as the work it
+/// covers so a rerun can pick up where a crashed run stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub stage: String,
+    /// The file being ingested. A checkpoint for a different file is stale.
+    pub source: String,
+    /// `source_version` of the file, which changes when it is replaced
+    pub source_version: String,
+    /// Stage specific position, e.g. items read from the file or the last
+    /// embedded rowid
+    pub position: i64,
+    pub added: i64,
+    pub changed: i64,
+    pub removed: i64,
+}
+
+impl Checkpoint {
+    pub fn new(stage: &str, source: &str, source_version: &str) -> Self {
+        Checkpoint {
+            stage: stage.to_string(),
+            source: source.to_string(),
+            source_version: source_version.to_string(),
+            position: 0,
+            added: 0,
+            changed: 0,
+            removed: 0,
+        }
+    }
+
+    /// Whether the checkpoint is for this version of the file. Positions in
+    /// any other file, even at the same path, mean nothing.
+    pub fn is_for(&self, source: &str, source_version: &str) -> bool {
+        self.source == source && self.source_version == source_version
+    }
+}
+
+/// Identifies a version of a file by its size and modification time. The bulk
+/// files are re-downloaded in place, so the path alone isn't enough.
+pub fn source_version(path: &str) -> Result<String> {
+    let metadata = fs::metadata(path).with_context(|| format!("Failed to read {}", path))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(format!("{}:{}", metadata.len(), modified.as_nanos()))
+}
+
+pub fn get_checkpoint(conn: &Connection, stage: &str) -> Result<Option<Checkpoint>> {
+    let mut stmt = conn.prepare(
+        "SELECT stage, source, source_version, position, added, changed, removed
+        FROM ingest_checkpoints
+        WHERE stage = ?;",
+    )?;
+    let mut rows = stmt.query(params![stage])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(Checkpoint {
+            stage: row.get(0)?,
+            source: row.get(1)?,
+            source_version: row.get(2)?,
+            position: row.get(3)?,
+            added: row.get(4)?,
+            changed: row.get(5)?,
+            removed: row.get(6)?,
+        })),
+        None => Ok(None),
+    }
+}
+
+pub fn save_checkpoint(conn: &Connection, checkpoint: &Checkpoint) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO ingest_checkpoints (
+            stage, source, source_version, position, added, changed, removed, updated_at
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP);",
+        params![
+            checkpoint.stage,
+            checkpoint.source,
+            checkpoint.source_version,
+            checkpoint.position,
+            checkpoint.added,
+            checkpoint.changed,
+            checkpoint.removed
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn clear_checkpoint(conn: &Connection, stage: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM ingest_checkpoints WHERE stage = ?;",
+        params![stage],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use rusqlite::{Connection, Result, Statement};
+
+use super::{CARD_COLUMNS, CARD_IMAGE_JOINS};
+
+/// Writes a card's row of the full text index from the card and its faces.
+/// Has to run after the faces are written.
+pub fn prep_upsert_card_fts(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare(
+        "INSERT OR REPLACE INTO card_fts (rowid, name, type_line, oracle_text, flavor_text)
+        SELECT
+            c.rowid,
+            c.name,
+            COALESCE(c.type_line, ''),
+            COALESCE(c.oracle_text, (SELECT group_concat(f.oracle_text, ' // ' ORDER BY f.face_index) FROM card_faces f WHERE f.card_id = c.id), ''),
+            COALESCE(c.flavor_text, (SELECT group_concat(f.flavor_text, ' // ' ORDER BY f.face_index) FROM card_faces f WHERE f.card_id = c.id), '')
+        FROM cards c
+        WHERE c.id = ?;",
+    )
+}
+
+/// Full text search ranked by bm25, with the name weighted above the type
+/// line, oracle and flavor text. The matches are materialized first since
+/// fts5's functions can't run inside the `GROUP BY`. `pagination` is what
+/// follows the `GROUP BY`, the `ORDER BY` and `LIMIT` of the page.
+pub fn paginated_full_text_search_sql(filters: &str, pagination: &str) -> String {
+    format!(
+        "
+    WITH matches AS MATERIALIZED (
+        SELECT
+            rowid,
+            snippet(card_fts, -1, '<mark>', '</mark>', '…', 16) AS snippet,
+            bm25(card_fts, 10.0, 2.0, 1.0, 0.5) AS rank
+        FROM card_fts
+        WHERE card_fts MATCH :search
+    )
+    SELECT {}, m.snippet, MIN(m.rank) AS rank
+    FROM matches as m
+    JOIN cards as c
+    ON c.rowid = m.rowid
+    {}
+    WHERE {}
+    GROUP BY c.name
+    {};
+    ",
+        CARD_COLUMNS, CARD_IMAGE_JOINS, filters, pagination
+    )
+}
+
+/// Turns a search into an fts5 query. Words are matched as separate terms,
+/// `"quoted words"` as a phrase and `word*` as a prefix. Every term is quoted
+/// so punctuation in the search, e.g. `+1/+1`, isn't read as query syntax.
+pub fn fts_query(search: &str) -> String {
+    let mut terms = Vec::new();
+    for (i, part) in search.split('"').enumerate() {
+        // Odd parts are inside quotes
+        if i % 2 == 1 {
+            if !part.trim().is_empty() {
+                terms.push(format!("\"{}\"", part.trim()));
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, "*"),
+                None => (word, ""),
+            };
+            if !word.is_empty() {
+                terms.push(format!("\"{}\"{}", word, prefix));
+            }
+        }
+    }
+    terms.join(" ")
+}
@@ -0,0 +1,272 @@
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection};
+
+/// A schema change, applied in its own transaction. The database's
+/// `PRAGMA user_version` is the version of the last applied migration.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order they are applied. Applied migrations must
+/// never change, add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "embedding_index",
+        sql: include_str!("migrations/0002_embedding_index.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "card_fts",
+        sql: include_str!("migrations/0003_card_fts.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "checkpoint_source_version",
+        sql: include_str!("migrations/0004_checkpoint_source_version.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "embedding_index_complete",
+        sql: include_str!("migrations/0005_embedding_index_complete.sql"),
+    },
+];
+
+/// The schema version this binary was built for
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("PRAGMA user_version;", [], |row| row.get(0))?)
+}
+
+/// Fails if the database was migrated by a newer binary, whose schema this
+/// one does not know how to use
+pub fn check_version(conn: &Connection) -> Result<()> {
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than the latest version {} this binary supports, upgrade the binary",
+            current,
+            latest_version()
+        ));
+    }
+    Ok(())
+}
+
+/// Applies the pending migrations up to and including `target`, returning the
+/// ones that were applied. There are no down migrations, so `target` can't be
+/// below the current version.
+pub fn migrate_to(conn: &Connection, target: i64) -> Result<Vec<&'static Migration>> {
+    check_version(conn)?;
+    let current = current_version(conn)?;
+    if target > latest_version() {
+        return Err(anyhow!(
+            "Unknown schema version {}, the latest is {}",
+            target,
+            latest_version()
+        ));
+    }
+    if target < current {
+        return Err(anyhow!(
+            "Database is already at schema version {}, migrations can't be undone",
+            current
+        ));
+    }
+
+    if current == 0 {
+        adopt_legacy_schema(conn)?;
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+    {
+        conn.execute_batch("BEGIN;")?;
+        let result = conn
+            .execute_batch(migration.sql)
+            .and_then(|_| conn.pragma_update(None, "user_version", migration.version));
+        if let Err(e) = result {
+            conn.execute_batch("ROLLBACK;")?;
+            return Err(e).with_context(|| {
+                format!("Migration {} {} failed", migration.version, migration.name)
+            });
+        }
+        conn.execute_batch("COMMIT;")?;
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+pub fn migrate(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    migrate_to(conn, latest_version())
+}
+
+/// Databases from before schema versioning have the tables of the initial
+/// schema, but maybe not every column of them
+fn adopt_legacy_schema(conn: &Connection) -> Result<()> {
+    let legacy: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'cards';",
+        [],
+        |row| row.get(0),
+    )?;
+    if !legacy {
+        return Ok(());
+    }
+
+    add_column_if_missing(conn, "sets", "parent_set_code", "TEXT")?;
+    add_column_if_missing(conn, "sets", "block", "TEXT")?;
+    add_column_if_missing(conn, "sets", "card_count", "INTEGER")?;
+    add_column_if_missing(conn, "sets", "digital", "BOOLEAN")?;
+    add_column_if_missing(conn, "sets", "icon_svg_uri", "TEXT")?;
+    add_column_if_missing(conn, "cards", "embedding_hash", "TEXT")?;
+    add_column_if_missing(conn, "cards", "colors", "INTEGER")?;
+    add_column_if_missing(conn, "cards", "color_identity", "INTEGER")?;
+    add_column_if_missing(conn, "cards", "produced_mana", "INTEGER")?;
+
+    // The first ingest stored these as json, `"123"` and `true`
+    conn.execute_batch(
+        "UPDATE cards SET digital = (digital = 'true') WHERE typeof(digital) = 'text';
+        UPDATE cards SET collector_number = trim(collector_number, '\"');",
+    )?;
+    Ok(())
+}
+
+/// Adds a column to a table created before the column existed
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool = conn.query_row(
+        &format!(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('{}') WHERE name = ?;",
+            table
+        ),
+        params![column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, definition
+            ),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::db::{card_from_row, open_conn, CARD_COLUMNS, CARD_IMAGE_JOINS};
+
+    /// The tables and values the first ingest, before schema versioning, wrote
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE sets (
+            code TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            set_type TEXT,
+            released_at DATE
+        );
+        CREATE TABLE cards (
+            id TEXT PRIMARY KEY,
+            oracle_id TEXT,
+            name TEXT NOT NULL,
+            lang TEXT,
+            released_at DATE,
+            mana_cost TEXT,
+            cmc REAL,
+            type_line TEXT,
+            oracle_text TEXT,
+            power TEXT,
+            toughness TEXT,
+            rarity TEXT,
+            flavor_text TEXT,
+            artist TEXT,
+            set_code TEXT,
+            collector_number TEXT,
+            digital BOOLEAN,
+            FOREIGN KEY (set_code) REFERENCES sets(code)
+        );
+        INSERT INTO sets (code, name) VALUES ('lea', 'Limited Edition Alpha');
+        INSERT INTO cards (id, name, set_code, collector_number, digital)
+        VALUES ('paper', 'Paper', 'lea', '\"123\"', 'false'),
+            ('digital', 'Digital', 'lea', '\"7a\"', 'true');
+    ";
+
+    fn memory_conn() -> Connection {
+        let settings = Settings {
+            db_path: String::from(":memory:"),
+            ..Settings::default()
+        };
+        open_conn(&settings).unwrap()
+    }
+
+    #[test]
+    fn migrates_a_new_database() {
+        let conn = memory_conn();
+        assert_eq!(migrate(&conn).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(migrate(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn adopts_and_normalizes_a_legacy_database() {
+        let conn = memory_conn();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        migrate(&conn).unwrap();
+
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM cards as c {}
+                ORDER BY CAST(c.collector_number AS INTEGER) DESC;",
+                CARD_COLUMNS, CARD_IMAGE_JOINS
+            ))
+            .unwrap();
+        let cards: Vec<(String, Option<String>, Option<bool>)> = stmt
+            .query_map([], card_from_row)
+            .unwrap()
+            .map(|card| {
+                let card = card.unwrap();
+                (card.id, card.collector_number, card.digital)
+            })
+            .collect();
+        assert_eq!(
+            cards,
+            vec![
+                (
+                    String::from("paper"),
+                    Some(String::from("123")),
+                    Some(false)
+                ),
+                (
+                    String::from("digital"),
+                    Some(String::from("7a")),
+                    Some(true)
+                ),
+            ]
+        );
+
+        let digital_type: String = conn
+            .query_row("SELECT DISTINCT typeof(digital) FROM cards;", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(digital_type, "integer");
+    }
+}
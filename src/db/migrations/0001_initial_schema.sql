@@ -0,0 +1,158 @@
+-- IF NOT EXISTS lets this adopt databases created before schema versioning
+CREATE TABLE IF NOT EXISTS sets (
+    code TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    set_type TEXT,
+    released_at DATE,
+    -- Filled in from the scryfall sets data, rather than from card rows
+    parent_set_code TEXT,
+    block TEXT,
+    card_count INTEGER,
+    digital BOOLEAN,
+    icon_svg_uri TEXT
+);
+
+CREATE TABLE IF NOT EXISTS cards (
+    id TEXT PRIMARY KEY,
+    oracle_id TEXT,
+    name TEXT NOT NULL,
+    lang TEXT,
+    released_at DATE,
+    mana_cost TEXT,
+    cmc REAL,
+    type_line TEXT,
+    oracle_text TEXT,
+    power TEXT,
+    toughness TEXT,
+    rarity TEXT,
+    flavor_text TEXT,
+    artist TEXT,
+    set_code TEXT,
+    collector_number TEXT,
+    digital BOOLEAN,
+    embedding_hash TEXT,
+    -- Bitmasks of `scryfall::color_mask`
+    colors INTEGER,
+    color_identity INTEGER,
+    produced_mana INTEGER,
+    FOREIGN KEY (set_code) REFERENCES sets(code)
+);
+CREATE INDEX IF NOT EXISTS idx_cards_set_code ON cards(set_code);
+
+CREATE TABLE IF NOT EXISTS card_keywords (
+    card_id TEXT NOT NULL,
+    keyword TEXT NOT NULL COLLATE NOCASE,
+    PRIMARY KEY (card_id, keyword),
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+CREATE INDEX IF NOT EXISTS idx_card_keywords_keyword ON card_keywords(keyword);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS card_vecs using vec0 (
+    embedding float[384]
+);
+
+CREATE TABLE IF NOT EXISTS image_uris (
+    card_id TEXT PRIMARY KEY,
+    small TEXT,
+    normal TEXT,
+    large TEXT,
+    png TEXT,
+    art_crop TEXT,
+    border_crop TEXT,
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+
+CREATE TABLE IF NOT EXISTS card_faces (
+    card_id TEXT NOT NULL,
+    face_index INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    mana_cost TEXT,
+    type_line TEXT,
+    oracle_text TEXT,
+    power TEXT,
+    toughness TEXT,
+    flavor_text TEXT,
+    artist TEXT,
+    small TEXT,
+    normal TEXT,
+    large TEXT,
+    png TEXT,
+    art_crop TEXT,
+    border_crop TEXT,
+    PRIMARY KEY (card_id, face_index),
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+
+CREATE TABLE IF NOT EXISTS card_legalities (
+    card_id TEXT NOT NULL,
+    format TEXT NOT NULL,
+    status TEXT NOT NULL,
+    PRIMARY KEY (card_id, format),
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+CREATE INDEX IF NOT EXISTS idx_card_legalities_format ON card_legalities(format, status);
+
+CREATE TABLE IF NOT EXISTS card_prices (
+    card_id TEXT NOT NULL,
+    snapshot_date DATE NOT NULL,
+    usd REAL,
+    usd_foil REAL,
+    usd_etched REAL,
+    eur REAL,
+    tix REAL,
+    PRIMARY KEY (card_id, snapshot_date),
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+
+CREATE TABLE IF NOT EXISTS card_relations (
+    card_id TEXT NOT NULL,
+    related_id TEXT NOT NULL,
+    component TEXT NOT NULL,
+    name TEXT NOT NULL,
+    type_line TEXT,
+    PRIMARY KEY (card_id, related_id),
+    FOREIGN KEY (card_id) REFERENCES cards(id)
+);
+
+CREATE TABLE IF NOT EXISTS rulings (
+    id INTEGER PRIMARY KEY,
+    oracle_id TEXT NOT NULL,
+    source TEXT NOT NULL,
+    published_at DATE NOT NULL,
+    comment TEXT NOT NULL,
+    embedded BOOLEAN NOT NULL DEFAULT 0,
+    UNIQUE (oracle_id, source, published_at, comment)
+);
+CREATE INDEX IF NOT EXISTS idx_rulings_oracle_id ON rulings(oracle_id);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS ruling_vecs using vec0 (
+    embedding float[384]
+);
+
+CREATE TABLE IF NOT EXISTS ingest_checkpoints (
+    stage TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    added INTEGER NOT NULL DEFAULT 0,
+    changed INTEGER NOT NULL DEFAULT 0,
+    removed INTEGER NOT NULL DEFAULT 0,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS ingest_seen_cards (
+    id TEXT PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS metadata (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS card_cluster_assigments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    card_rowid INTEGER NOT NULL,
+    cluster_id INTEGER NOT NULL,
+    assigment_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS idx_card_cluster_assigments_cluster_id ON card_cluster_assigments(cluster_id);
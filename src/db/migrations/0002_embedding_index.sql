@@ -0,0 +1,3 @@
+-- The vec tables of the initial schema were sized for fastembed's default model
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('embedding_model', 'bge-small-en-v1.5');
+INSERT OR IGNORE INTO metadata (key, value) VALUES ('embedding_dim', '384');
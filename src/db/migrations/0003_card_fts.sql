@@ -0,0 +1,19 @@
+-- Full text index over the card text, keyed by the card rowid. It is kept in
+-- sync by the ingest, since a card's faces are written after the card itself.
+CREATE VIRTUAL TABLE card_fts USING fts5 (
+    name,
+    type_line,
+    oracle_text,
+    flavor_text,
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+);
+
+INSERT INTO card_fts (rowid, name, type_line, oracle_text, flavor_text)
+SELECT
+    c.rowid,
+    c.name,
+    COALESCE(c.type_line, ''),
+    COALESCE(c.oracle_text, (SELECT group_concat(f.oracle_text, ' // ' ORDER BY f.face_index) FROM card_faces f WHERE f.card_id = c.id), ''),
+    COALESCE(c.flavor_text, (SELECT group_concat(f.flavor_text, ' // ' ORDER BY f.face_index) FROM card_faces f WHERE f.card_id = c.id), '')
+FROM cards c;
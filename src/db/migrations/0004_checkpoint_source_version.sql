@@ -0,0 +1,4 @@
+-- The size and modification time of the checkpointed file, so a file that was
+-- replaced at the same path isn't resumed part way through. Checkpoints saved
+-- before this have none and are never resumed.
+ALTER TABLE ingest_checkpoints ADD COLUMN source_version TEXT NOT NULL DEFAULT '';
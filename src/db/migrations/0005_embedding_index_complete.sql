@@ -0,0 +1,10 @@
+-- Vec tables are only searched once an ingest has embedded every row of them.
+-- Indexes from before this are taken as complete if their last build finished.
+INSERT OR IGNORE INTO metadata (key, value)
+SELECT 'card_vecs_complete', '1'
+WHERE EXISTS (SELECT 1 FROM metadata WHERE key = 'embedding_text_version');
+
+INSERT OR IGNORE INTO metadata (key, value)
+SELECT 'ruling_vecs_complete', '1'
+WHERE EXISTS (SELECT 1 FROM rulings)
+AND NOT EXISTS (SELECT 1 FROM rulings WHERE embedded = 0);
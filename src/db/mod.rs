@@ -1,104 +1,98 @@
+pub mod checkpoints;
+pub mod fts;
+pub mod migrations;
+pub mod rulings;
 pub mod vectors;
 
 use anyhow::{anyhow, Context, Result};
+use fts::{fts_query, paginated_full_text_search_sql};
 use indicatif::{ProgressBar, ProgressStyle};
-use rusqlite::{ffi::sqlite3_auto_extension, named_params, params, Connection};
+use rusqlite::{
+    ffi::sqlite3_auto_extension,
+    named_params, params,
+    types::{Value, ValueRef},
+    Connection, ToSql,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use sqlite_vec::sqlite3_vec_init;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
 use tokio::sync::Mutex;
-use vectors::{Point, SELECT_PAGINATED_SEMANTIC_SEARCH};
+use vectors::{check_embedding_index, paginated_semantic_search_sql, Point, VecTable};
 
-use crate::embedings::{init, string_to_embedding};
+use crate::config::Settings;
+use crate::embedings::QueryEmbedding;
+use crate::query::Query;
+use crate::scryfall::mask_to_colors;
 
 // Wrapper for SQLite connection
 pub struct DbConnection(pub Mutex<Connection>);
 
-pub fn init_conn() -> Result<Connection> {
+/// Opens the database without touching its schema
+pub fn open_conn(settings: &Settings) -> Result<Connection> {
     unsafe {
         sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
     };
     println!("Mounted sqlite-vec");
 
-    let conn = Connection::open("./data/scryfall_cards.db")?;
+    let conn = Connection::open(&settings.db_path)?;
+    // Lets readers keep going while the ingest, or another thread, is writing
+    conn.pragma_update(None, "journal_mode", "WAL")?;
 
     let sqlite_vec_test: String = conn.query_row("SELECT vec_version();", [], |row| row.get(0))?;
     println!("{}", sqlite_vec_test);
 
-    conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS sets (
-            code TEXT PRIMARY KEY,
-            name TEXT NOT NULL,
-            set_type TEXT,
-            released_at DATE
-        );
-        ",
-        [],
-    )?;
-    conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS cards (
-            id TEXT PRIMARY KEY,
-            oracle_id TEXT,
-            name TEXT NOT NULL,
-            lang TEXT,
-            released_at DATE,
-            mana_cost TEXT,
-            cmc REAL,
-            type_line TEXT,
-            oracle_text TEXT,
-            power TEXT,
-            toughness TEXT,
-            rarity TEXT,
-            flavor_text TEXT,
-            artist TEXT,
-            set_code TEXT,
-            collector_number TEXT,
-            digital BOOLEAN,
-            FOREIGN KEY (set_code) REFERENCES sets(code)
-        );
-
-        CREATE INDEX IF NOT EXISTS idx_cards_set_code ON cards(set_code);
-    ",
-        [],
-    )?;
+    Ok(conn)
+}
 
-    conn.execute(
-        "CREATE VIRTUAL TABLE IF NOT EXISTS card_vecs using vec0 (
-            embedding float[384]
-        )",
-        [],
-    )?;
+/// Opens the database and migrates it to the latest schema
+pub fn init_conn(settings: &Settings) -> Result<Connection> {
+    let conn = open_conn(settings)?;
+    for migration in migrations::migrate(&conn)? {
+        println!("Applied migration {} {}", migration.version, migration.name);
+    }
+    Ok(conn)
+}
 
+pub fn set_metadata(conn: &Connection, key: &str, value: &str) -> Result<()> {
     conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS image_uris (
-            card_id TEXT PRIMARY KEY,
-            small TEXT,
-            normal TEXT,
-            large TEXT,
-            png TEXT,
-            art_crop TEXT,
-            border_crop TEXT,
-            FOREIGN KEY (card_id) REFERENCES cards(id)
-        );
-    ",
-        [],
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES (?, ?);",
+        params![key, value],
     )?;
+    Ok(())
+}
 
-    conn.execute("
-        CREATE TABLE IF NOT EXISTS card_cluster_assigments (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            card_rowid INTEGER NOT NULL,
-            cluster_id INTEGER NOT NULL,
-            assigment_id INTEGER NOT NULL,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        );
+pub fn get_metadata(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT value FROM metadata WHERE key = ?;")?;
+    let mut rows = stmt.query(params![key])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
 
-        CREATE INDEX IF NOT EXISTS idx_card_cluster_assigments_cluster_id ON card_cluster_assigments(cluster_id);
-    ", [])?;
+/// The scryfall dataset the database was last built from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dataset {
+    pub bulk_type: Option<String>,
+    pub source_file: Option<String>,
+    pub languages: Option<String>,
+    pub ingested_at: Option<String>,
+    pub card_count: i64,
+    pub embedding_index: Option<vectors::EmbeddingIndex>,
+}
 
-    Ok(conn)
+pub fn get_dataset(conn: &Connection) -> Result<Dataset> {
+    Ok(Dataset {
+        bulk_type: get_metadata(conn, "bulk_type")?,
+        source_file: get_metadata(conn, "source_file")?,
+        languages: get_metadata(conn, "languages")?,
+        ingested_at: get_metadata(conn, "ingested_at")?,
+        card_count: conn.query_row("SELECT COUNT(*) FROM cards;", [], |row| row.get(0))?,
+        embedding_index: vectors::get_embedding_index(conn)?,
+    })
 }
 
 pub fn prep_insert_card_cluster_assigments(
@@ -148,16 +142,303 @@ pub fn prep_insert_image_uris(conn: &Connection) -> rusqlite::Result<rusqlite::S
     )
 }
 
+pub fn prep_insert_card_face(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare(
+        "INSERT OR REPLACE INTO card_faces (
+            card_id, face_index, name, mana_cost, type_line, oracle_text, power, toughness,
+            flavor_text, artist, small, normal, large, png, art_crop, border_crop
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+    )
+}
+
+pub fn prep_delete_card_faces(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("DELETE FROM card_faces WHERE card_id = ?;")
+}
+
+pub fn prep_insert_card_legality(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare(
+        "INSERT OR REPLACE INTO card_legalities (card_id, format, status) VALUES (?, ?, ?);",
+    )
+}
+
+pub fn prep_delete_card_legalities(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("DELETE FROM card_legalities WHERE card_id = ?;")
+}
+
+/// Re-running an ingest on the same day replaces that day's snapshot
+pub fn prep_insert_card_prices(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare(
+        "INSERT OR REPLACE INTO card_prices (
+            card_id, snapshot_date, usd, usd_foil, usd_etched, eur, tix
+        ) VALUES (?, ?, ?, ?, ?, ?, ?);",
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardPrices {
+    pub snapshot_date: String,
+    pub usd: Option<f64>,
+    pub usd_foil: Option<f64>,
+    pub usd_etched: Option<f64>,
+    pub eur: Option<f64>,
+    pub tix: Option<f64>,
+}
+
+/// Price history of a card, oldest snapshot first. `None` if there is no such card.
+pub fn get_card_prices(conn: &Connection, card_id: &str) -> Result<Option<Vec<CardPrices>>> {
+    if !card_exists(conn, card_id)? {
+        return Ok(None);
+    }
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT snapshot_date, usd, usd_foil, usd_etched, eur, tix
+            FROM card_prices
+            WHERE card_id = ?
+            ORDER BY snapshot_date;",
+        )
+        .context("Failed to prepare card prices")?;
+    let prices = stmt
+        .query_map(params![card_id], |row| {
+            Ok(CardPrices {
+                snapshot_date: row.get(0)?,
+                usd: row.get(1)?,
+                usd_foil: row.get(2)?,
+                usd_etched: row.get(3)?,
+                eur: row.get(4)?,
+                tix: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<CardPrices>>>()?;
+
+    Ok(Some(prices))
+}
+
+pub fn card_exists(conn: &Connection, card_id: &str) -> Result<bool> {
+    Ok(prep_card_exists(conn)?.query_row(params![card_id], |row| row.get(0))?)
+}
+
+pub fn get_card_oracle_id(conn: &Connection, card_id: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT oracle_id FROM cards WHERE id = ?;")?;
+    let mut rows = stmt.query(params![card_id])?;
+    match rows.next()? {
+        Some(row) => Ok(row.get(0)?),
+        None => Ok(None),
+    }
+}
+
+pub fn prep_insert_card_keyword(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("INSERT OR IGNORE INTO card_keywords (card_id, keyword) VALUES (?, ?);")
+}
+
+pub fn prep_delete_card_keywords(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("DELETE FROM card_keywords WHERE card_id = ?;")
+}
+
+pub fn prep_insert_card_relation(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare(
+        "INSERT OR REPLACE INTO card_relations (
+            card_id, related_id, component, name, type_line
+        ) VALUES (?, ?, ?, ?, ?);",
+    )
+}
+
+pub fn prep_delete_card_relations(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("DELETE FROM card_relations WHERE card_id = ?;")
+}
+
+/// A card related to another through scryfall's `all_parts`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardRelation {
+    pub id: String,
+    /// `token`, `meld_part`, `meld_result` or `combo_piece`
+    pub component: String,
+    pub name: String,
+    pub type_line: Option<String>,
+}
+
+/// Cards related to a card. `None` if there is no such card.
+pub fn get_card_relations(conn: &Connection, card_id: &str) -> Result<Option<Vec<CardRelation>>> {
+    if !card_exists(conn, card_id)? {
+        return Ok(None);
+    }
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT related_id, component, name, type_line
+            FROM card_relations
+            WHERE card_id = ?
+            ORDER BY component, name;",
+        )
+        .context("Failed to prepare card relations")?;
+    let relations = stmt
+        .query_map(params![card_id], |row| {
+            Ok(CardRelation {
+                id: row.get(0)?,
+                component: row.get(1)?,
+                name: row.get(2)?,
+                type_line: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<CardRelation>>>()?;
+
+    Ok(Some(relations))
+}
+
+/// A token some cards in a deck can create
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeckToken {
+    /// One print of the token
+    pub id: String,
+    pub name: String,
+    pub type_line: Option<String>,
+    /// Names of the deck's cards that create the token
+    pub created_by: Vec<String>,
+}
+
+/// Every token the named cards can create. Names match either the full card
+/// name or the front face of a multi-faced card.
+pub fn get_deck_tokens(conn: &Connection, card_names: &[String]) -> Result<Vec<DeckToken>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT r.related_id, r.name, r.type_line, c.name
+            FROM cards as c
+            JOIN card_relations as r
+            ON r.card_id = c.id
+            WHERE r.component = 'token'
+            AND (c.name = :name COLLATE NOCASE OR EXISTS (
+                SELECT 1 FROM card_faces as f
+                WHERE f.card_id = c.id AND f.face_index = 0 AND f.name = :name COLLATE NOCASE
+            ))
+            ORDER BY r.related_id;",
+        )
+        .context("Failed to prepare deck tokens")?;
+
+    // Keyed by name and type line, as every print of a token has its own id
+    let mut tokens: BTreeMap<(String, Option<String>), DeckToken> = BTreeMap::new();
+    for card_name in card_names {
+        let mut rows = stmt.query(named_params! {":name": card_name})?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(1)?;
+            let type_line: Option<String> = row.get(2)?;
+            let creator: String = row.get(3)?;
+            let token = tokens
+                .entry((name.clone(), type_line.clone()))
+                .or_insert_with(|| DeckToken {
+                    id: String::new(),
+                    name,
+                    type_line,
+                    created_by: Vec::new(),
+                });
+            if token.id.is_empty() {
+                token.id = row.get(0)?;
+            }
+            if !token.created_by.contains(&creator) {
+                token.created_by.push(creator);
+            }
+        }
+    }
+
+    Ok(tokens.into_values().collect())
+}
+
+/// Upserts a card. Updating in place keeps the card's rowid, which its
+/// embedding in `card_vecs` is keyed on.
 pub fn prep_insert_card(conn: &Connection) -> rusqlite::Result<rusqlite::Statement> {
     conn.prepare(
-        "INSERT OR REPLACE INTO cards (
+        "INSERT INTO cards (
             id, oracle_id, name, lang, released_at, mana_cost, cmc,
             type_line, oracle_text, power, toughness, rarity, flavor_text, artist,
-            set_code, collector_number, digital
-        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            set_code, collector_number, digital, colors, color_identity, produced_mana
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (id) DO UPDATE SET
+            oracle_id = excluded.oracle_id,
+            name = excluded.name,
+            lang = excluded.lang,
+            released_at = excluded.released_at,
+            mana_cost = excluded.mana_cost,
+            cmc = excluded.cmc,
+            type_line = excluded.type_line,
+            oracle_text = excluded.oracle_text,
+            power = excluded.power,
+            toughness = excluded.toughness,
+            rarity = excluded.rarity,
+            flavor_text = excluded.flavor_text,
+            artist = excluded.artist,
+            set_code = excluded.set_code,
+            collector_number = excluded.collector_number,
+            digital = excluded.digital,
+            colors = excluded.colors,
+            color_identity = excluded.color_identity,
+            produced_mana = excluded.produced_mana;",
     )
 }
 
+pub fn prep_card_exists(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("SELECT EXISTS (SELECT 1 FROM cards WHERE id = ?);")
+}
+
+/// Clears the card ids seen by a previous ingest. The seen ids are kept in a
+/// real table, rather than a temp one, so they survive a resumed ingest.
+pub fn clear_seen_cards(conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM ingest_seen_cards;", [])?;
+    Ok(())
+}
+
+pub fn prep_insert_seen_card(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare("INSERT OR IGNORE INTO ingest_seen_cards (id) VALUES (?);")
+}
+
+/// Deletes every card, and the rows hanging off of it, that was not seen
+/// during the ingest. Returns the number of removed cards.
+pub fn delete_unseen_cards(conn: &Connection) -> Result<usize> {
+    const UNSEEN: &str = "SELECT id FROM cards WHERE id NOT IN (SELECT id FROM ingest_seen_cards)";
+
+    conn.execute(
+        &format!(
+            "DELETE FROM card_vecs WHERE rowid IN (SELECT rowid FROM cards WHERE id IN ({}));",
+            UNSEEN
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "DELETE FROM card_fts WHERE rowid IN (SELECT rowid FROM cards WHERE id IN ({}));",
+            UNSEEN
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM image_uris WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM card_faces WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM card_legalities WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM card_prices WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM card_keywords WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM card_relations WHERE card_id IN ({});", UNSEEN),
+        [],
+    )?;
+    let removed = conn.execute(
+        "DELETE FROM cards WHERE id NOT IN (SELECT id FROM ingest_seen_cards);",
+        [],
+    )?;
+    Ok(removed)
+}
+
 pub fn prep_insert_card_vec(conn: &Connection) -> rusqlite::Result<rusqlite::Statement> {
     conn.prepare(
         "INSERT OR REPLACE INTO card_vecs (
@@ -166,23 +447,187 @@ pub fn prep_insert_card_vec(conn: &Connection) -> rusqlite::Result<rusqlite::Sta
     )
 }
 
+/// Upserts the set fields that every card carries, leaving the rest of the
+/// set metadata alone
 pub fn prep_insert_set(conn: &Connection) -> rusqlite::Result<rusqlite::Statement> {
     conn.prepare(
-        "INSERT OR REPLACE INTO sets (code, name, set_type, released_at) VALUES (?, ?, ?, ?);",
+        "INSERT INTO sets (code, name, set_type, released_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (code) DO UPDATE SET
+            name = excluded.name,
+            set_type = excluded.set_type,
+            released_at = COALESCE(sets.released_at, excluded.released_at);",
+    )
+}
+
+pub fn prep_insert_set_metadata(conn: &Connection) -> rusqlite::Result<rusqlite::Statement<'_>> {
+    conn.prepare(
+        "INSERT INTO sets (
+            code, name, set_type, released_at, parent_set_code, block, card_count, digital,
+            icon_svg_uri
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (code) DO UPDATE SET
+            name = excluded.name,
+            set_type = excluded.set_type,
+            released_at = excluded.released_at,
+            parent_set_code = excluded.parent_set_code,
+            block = excluded.block,
+            card_count = excluded.card_count,
+            digital = excluded.digital,
+            icon_svg_uri = excluded.icon_svg_uri;",
     )
 }
 
-const SELECT_ALL_CARDS: &str = "
-    SELECT 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Set {
+    pub code: String,
+    pub name: String,
+    pub set_type: Option<String>,
+    pub released_at: Option<String>,
+    pub parent_set_code: Option<String>,
+    pub block: Option<String>,
+    pub card_count: Option<i64>,
+    pub digital: Option<bool>,
+    pub icon_svg_uri: Option<String>,
+}
+
+const SELECT_SETS: &str = "
+    SELECT code, name, set_type, released_at, parent_set_code, block, card_count, digital,
+        icon_svg_uri
+    FROM sets
+";
+
+fn set_from_row(row: &rusqlite::Row) -> rusqlite::Result<Set> {
+    Ok(Set {
+        code: row.get(0)?,
+        name: row.get(1)?,
+        set_type: row.get(2)?,
+        released_at: row.get(3)?,
+        parent_set_code: row.get(4)?,
+        block: row.get(5)?,
+        card_count: row.get(6)?,
+        digital: row.get(7)?,
+        icon_svg_uri: row.get(8)?,
+    })
+}
+
+/// Every set, newest first
+pub fn get_sets(conn: &Connection) -> Result<Vec<Set>> {
+    let mut stmt = conn
+        .prepare(&format!("{} ORDER BY released_at DESC, name;", SELECT_SETS))
+        .context("Failed to prepare sets")?;
+    let sets = stmt
+        .query_map([], set_from_row)?
+        .collect::<rusqlite::Result<Vec<Set>>>()?;
+    Ok(sets)
+}
+
+pub fn get_set(conn: &Connection, code: &str) -> Result<Option<Set>> {
+    let mut stmt = conn
+        .prepare(&format!("{} WHERE code = ? COLLATE NOCASE;", SELECT_SETS))
+        .context("Failed to prepare set")?;
+    let mut rows = stmt.query(params![code])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(set_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// A page of the cards in a set, in collector number order. Collector numbers
+/// can have letters and symbols, e.g. `123a` or `★12`, so they sort by their
+/// leading number first.
+pub fn get_set_cards(
+    conn: &Connection,
+    code: &str,
+    page: u32,
+    page_size: u32,
+) -> Result<Vec<Card>> {
+    // Pages before the first are the first, and ones past any set are empty
+    let offset = page.saturating_sub(1).saturating_mul(page_size);
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM cards as c {}
+            WHERE c.set_code = ? COLLATE NOCASE
+            ORDER BY CAST(c.collector_number AS INTEGER), c.collector_number
+            LIMIT ? OFFSET ?;",
+            CARD_COLUMNS, CARD_IMAGE_JOINS
+        ))
+        .context("Failed to prepare set cards")?;
+    let mut cards = stmt
+        .query_map(params![code, page_size, offset], card_from_row)?
+        .collect::<rusqlite::Result<Vec<Card>>>()?;
+
+    attach_card_details(conn, &mut cards)?;
+    Ok(cards)
+}
+
+/// The columns of a `Card`, in the order `card_from_row` reads them. Expects
+/// the cards table as `c` and `CARD_IMAGE_JOINS`.
+pub const CARD_COLUMNS: &str = "
         c.id, c.oracle_id, c.name, c.lang, 
         c.released_at, c.mana_cost, c.cmc, 
         c.type_line, c.oracle_text, c.power, 
         c.toughness, c.rarity, c.flavor_text, 
         c.artist, c.set_code, c.collector_number, 
-        c.digital, iu.normal
-    FROM cards as c
-    JOIN image_uris as iu
-    ON c.id = iu.card_id 
+        c.digital, COALESCE(iu.normal, cf.normal),
+        c.colors, c.color_identity, c.produced_mana,
+        (SELECT p.usd FROM card_prices as p WHERE p.card_id = c.id ORDER BY p.snapshot_date DESC LIMIT 1)
+";
+
+/// Multi-faced cards only have images on their faces, so fall back to the front face
+pub const CARD_IMAGE_JOINS: &str = "
+    LEFT JOIN image_uris as iu
+    ON c.id = iu.card_id
+    LEFT JOIN card_faces as cf
+    ON c.id = cf.card_id AND cf.face_index = 0
+";
+
+pub fn card_from_row(row: &rusqlite::Row) -> rusqlite::Result<Card> {
+    Ok(Card {
+        id: row.get(0)?,
+        oracle_id: row.get(1)?,
+        name: row.get(2)?,
+        lang: row.get(3)?,
+        released_at: row.get(4)?,
+        mana_cost: row.get(5)?,
+        cmc: row.get(6)?,
+        type_line: row.get(7)?,
+        oracle_text: row.get(8)?,
+        power: row.get(9)?,
+        toughness: row.get(10)?,
+        rarity: row.get(11)?,
+        flavor_text: row.get(12)?,
+        artist: row.get(13)?,
+        set_code: row.get(14)?,
+        collector_number: row.get(15)?,
+        // Databases ingested before cards were typed stored it as text
+        digital: match row.get_ref(16)? {
+            ValueRef::Text(text) => Some(text == b"true"),
+            _ => row.get(16)?,
+        },
+        image_url: row.get(17)?,
+        colors: row.get::<_, Option<i64>>(18)?.map(mask_to_colors),
+        color_identity: row.get::<_, Option<i64>>(19)?.map(mask_to_colors),
+        produced_mana: row.get::<_, Option<i64>>(20)?.map(mask_to_colors),
+        usd: row.get(21)?,
+        keywords: Vec::new(),
+        faces: Vec::new(),
+        legalities: BTreeMap::new(),
+        distance: None,
+        score: None,
+        snippet: None,
+        bm25: None,
+    })
+}
+
+/// Matches cards whose faces match `:search`, for multi-faced cards that have
+/// no top level oracle text
+const FACES_LIKE_SEARCH: &str = "
+    EXISTS (
+        SELECT 1 FROM card_faces as f
+        WHERE f.card_id = c.id
+        AND (f.name LIKE :search COLLATE NOCASE or f.oracle_text LIKE :search COLLATE NOCASE or f.flavor_text LIKE :search COLLATE NOCASE)
+    )
 ";
 
 pub fn get_random_image_uris(
@@ -206,7 +651,7 @@ pub fn get_random_image_uris(
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Card {
     pub id: String,
-    pub oracle_id: String,
+    pub oracle_id: Option<String>,
     pub name: String,
     pub lang: Option<String>,
     pub released_at: Option<String>,
@@ -221,97 +666,950 @@ pub struct Card {
     pub artist: Option<String>,
     pub set_code: Option<String>,
     pub collector_number: Option<String>,
-    pub digital: Option<String>,
+    pub digital: Option<bool>,
+    pub image_url: Option<String>,
+    pub colors: Option<Vec<String>>,
+    pub color_identity: Option<Vec<String>>,
+    pub produced_mana: Option<Vec<String>>,
+    /// Latest usd price
+    pub usd: Option<f64>,
+    pub keywords: Vec<String>,
+    pub faces: Vec<CardFace>,
+    /// Status keyed by format, e.g. `modern -> legal`
+    pub legalities: BTreeMap<String, String>,
+    /// Distance to the query embedding, for semantic and hybrid searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+    /// Reciprocal rank fusion score, for hybrid searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// The matched text with the matches in `<mark>`, for full text searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// bm25 rank, lower is a better match, for full text searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bm25: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CardFace {
+    pub name: String,
+    pub mana_cost: Option<String>,
+    pub type_line: Option<String>,
+    pub oracle_text: Option<String>,
+    pub power: Option<String>,
+    pub toughness: Option<String>,
+    pub flavor_text: Option<String>,
+    pub artist: Option<String>,
     pub image_url: Option<String>,
 }
 
+/// Fills in the faces and legalities of each card. Single faced cards are left
+/// with no faces.
+pub fn attach_card_details(conn: &Connection, cards: &mut [Card]) -> Result<()> {
+    attach_card_faces(conn, cards)?;
+    attach_card_legalities(conn, cards)?;
+    attach_card_keywords(conn, cards)?;
+    Ok(())
+}
+
+fn attach_card_keywords(conn: &Connection, cards: &mut [Card]) -> Result<()> {
+    let mut stmt = conn
+        .prepare("SELECT keyword FROM card_keywords WHERE card_id = ? ORDER BY keyword;")
+        .context("Failed to prepare card keywords")?;
+
+    for card in cards.iter_mut() {
+        card.keywords = stmt
+            .query_map(params![card.id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+    }
+
+    Ok(())
+}
+
+fn attach_card_legalities(conn: &Connection, cards: &mut [Card]) -> Result<()> {
+    let mut stmt = conn
+        .prepare("SELECT format, status FROM card_legalities WHERE card_id = ?;")
+        .context("Failed to prepare card legalities")?;
+
+    for card in cards.iter_mut() {
+        card.legalities = stmt
+            .query_map(params![card.id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<BTreeMap<String, String>>>()?;
+    }
+
+    Ok(())
+}
+
+fn attach_card_faces(conn: &Connection, cards: &mut [Card]) -> Result<()> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT name, mana_cost, type_line, oracle_text, power, toughness, flavor_text, artist, normal
+            FROM card_faces
+            WHERE card_id = ?
+            ORDER BY face_index;",
+        )
+        .context("Failed to prepare card faces")?;
+
+    for card in cards.iter_mut() {
+        card.faces = stmt
+            .query_map(params![card.id], |row| {
+                Ok(CardFace {
+                    name: row.get(0)?,
+                    mana_cost: row.get(1)?,
+                    type_line: row.get(2)?,
+                    oracle_text: row.get(3)?,
+                    power: row.get(4)?,
+                    toughness: row.get(5)?,
+                    flavor_text: row.get(6)?,
+                    artist: row.get(7)?,
+                    image_url: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<CardFace>>>()?;
+    }
+
+    Ok(())
+}
+
+/// How `search_cards` matches the search text, the `mode` of `/api/cards`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CardSearchType {
+    /// Nearest card embeddings to the query embedding
     Semantic,
+    /// Substring of the name, oracle or flavor text
+    #[default]
     Like,
+    /// `FullText` and `Semantic` rankings merged with reciprocal rank fusion
+    Hybrid,
+    /// Terms, `"phrases"` and `prefixes*` in the full text index, ranked by bm25
+    #[serde(rename = "fts")]
+    FullText,
+}
+
+/// Dampens how much the top ranks of one ranking outweigh the rest, 60 is
+/// the value from the original reciprocal rank fusion paper
+const RRF_K: f64 = 60.0;
+
+/// Filters applied on top of a card search. `None` fields don't filter.
+#[derive(Debug, Default, Clone)]
+pub struct CardFilters {
+    /// Only cards that are legal or restricted in this format
+    pub format: Option<String>,
+    /// Bounds on the latest usd price
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    /// Exactly these colors, as a `scryfall::color_mask`
+    pub colors: Option<i64>,
+    /// Color identity within these colors, as a `scryfall::color_mask`
+    pub identity: Option<i64>,
+    /// Produces at least these colors of mana, as a `scryfall::color_mask`
+    pub produces: Option<i64>,
+    pub keyword: Option<String>,
+    /// Set code, e.g. `znr`
+    pub set: Option<String>,
+    pub rarity: Option<String>,
+    pub cmc_min: Option<f64>,
+    pub cmc_max: Option<f64>,
+    /// Substring of the type line, e.g. `creature`
+    pub type_line: Option<String>,
+    /// Substring of the artist
+    pub artist: Option<String>,
+    /// A parsed `query` search, e.g. `t:creature c:g cmc<=3`
+    pub query: Option<Query>,
+}
+
+/// Conditions on `c`, the cards table, for each of the `CardFilters`
+pub const CARD_FILTERS: &str = "
+    (:format IS NULL OR EXISTS (
+        SELECT 1 FROM card_legalities as l
+        WHERE l.card_id = c.id AND l.format = :format AND l.status IN ('legal', 'restricted')
+    ))
+    AND (:min_price IS NULL OR (
+        SELECT p.usd FROM card_prices as p WHERE p.card_id = c.id ORDER BY p.snapshot_date DESC LIMIT 1
+    ) >= :min_price)
+    AND (:max_price IS NULL OR (
+        SELECT p.usd FROM card_prices as p WHERE p.card_id = c.id ORDER BY p.snapshot_date DESC LIMIT 1
+    ) <= :max_price)
+    AND (:colors IS NULL OR c.colors = :colors)
+    AND (:identity IS NULL OR (c.color_identity | :identity) = :identity)
+    AND (:produces IS NULL OR (c.produced_mana & :produces) = :produces)
+    AND (:keyword IS NULL OR EXISTS (
+        SELECT 1 FROM card_keywords as k WHERE k.card_id = c.id AND k.keyword = :keyword
+    ))
+    AND (:set IS NULL OR c.set_code = :set COLLATE NOCASE)
+    AND (:rarity IS NULL OR c.rarity = :rarity COLLATE NOCASE)
+    AND (:cmc_min IS NULL OR c.cmc >= :cmc_min)
+    AND (:cmc_max IS NULL OR c.cmc <= :cmc_max)
+    AND (:type_line IS NULL OR c.type_line LIKE '%' || :type_line || '%' COLLATE NOCASE OR EXISTS (
+        SELECT 1 FROM card_faces as tf
+        WHERE tf.card_id = c.id AND tf.type_line LIKE '%' || :type_line || '%' COLLATE NOCASE
+    ))
+    AND (:artist IS NULL OR c.artist LIKE '%' || :artist || '%' COLLATE NOCASE OR EXISTS (
+        SELECT 1 FROM card_faces as af
+        WHERE af.card_id = c.id AND af.artist LIKE '%' || :artist || '%' COLLATE NOCASE
+    ))
+";
+
+impl CardFilters {
+    /// `CARD_FILTERS` and the compiled query, with the query's own parameters
+    fn sql(&self) -> (String, Vec<(String, Value)>) {
+        match &self.query {
+            Some(query) => {
+                let compiled = query.to_sql();
+                (
+                    format!("{} AND {}", CARD_FILTERS, compiled.sql),
+                    compiled.params,
+                )
+            }
+            None => (CARD_FILTERS.to_string(), Vec::new()),
+        }
+    }
 }
 
+/// What a card search is ordered by, the `sort` of `/api/cards`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardSort {
+    Name,
+    /// Release date
+    Date,
+    /// Set, then collector number
+    Collector,
+    Cmc,
+    /// Latest usd price
+    Price,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl CardSort {
+    /// The sort key, as expressions on `c`, the cards table
+    fn keys(&self) -> &'static [&'static str] {
+        match self {
+            CardSort::Name => &["c.name"],
+            CardSort::Date => &["c.released_at"],
+            // Collector numbers can have letters and symbols, e.g. `123a`
+            CardSort::Collector => &[
+                "c.set_code",
+                "CAST(c.collector_number AS INTEGER)",
+                "c.collector_number",
+            ],
+            CardSort::Cmc => &["c.cmc"],
+            CardSort::Price => &["(SELECT p.usd FROM card_prices as p WHERE p.card_id = c.id ORDER BY p.snapshot_date DESC LIMIT 1)"],
+        }
+    }
+
+    /// The `keys` of a card, as sql computes them
+    fn key_values(&self, card: &Card) -> Vec<JsonValue> {
+        match self {
+            CardSort::Name => vec![card.name.clone().into()],
+            CardSort::Date => vec![card.released_at.clone().into()],
+            CardSort::Collector => vec![
+                card.set_code.clone().into(),
+                collector_number_prefix(&card.collector_number).into(),
+                card.collector_number.clone().into(),
+            ],
+            CardSort::Cmc => vec![card.cmc.into()],
+            CardSort::Price => vec![card.usd.into()],
+        }
+    }
+
+    /// The same order as `keyset_order_by`, for cards that were ranked outside of sql
+    fn compare(&self, a: &Card, b: &Card, order: SortOrder) -> Ordering {
+        fn by<T: PartialOrd>(a: Option<T>, b: Option<T>, order: SortOrder) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => {
+                    let ordering = a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+                    match order {
+                        SortOrder::Asc => ordering,
+                        SortOrder::Desc => ordering.reverse(),
+                    }
+                }
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        }
+
+        let ordering = match self {
+            CardSort::Name => by(Some(&a.name), Some(&b.name), order),
+            CardSort::Date => by(a.released_at.as_ref(), b.released_at.as_ref(), order),
+            CardSort::Collector => by(a.set_code.as_ref(), b.set_code.as_ref(), order)
+                .then(by(
+                    collector_number_prefix(&a.collector_number),
+                    collector_number_prefix(&b.collector_number),
+                    order,
+                ))
+                .then(by(
+                    a.collector_number.as_ref(),
+                    b.collector_number.as_ref(),
+                    order,
+                )),
+            CardSort::Cmc => by(a.cmc, b.cmc, order),
+            CardSort::Price => by(a.usd, b.usd, order),
+        };
+        ordering.then_with(|| a.name.cmp(&b.name))
+    }
+}
+
+/// The leading number of a collector number, like sqlite's `CAST(... AS INTEGER)`
+fn collector_number_prefix(number: &Option<String>) -> Option<i64> {
+    let digits: String = number
+        .as_deref()?
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    Some(digits.parse().unwrap_or(0))
+}
+
+/// The terms a keyset paged search is ordered by, as (expression, descending).
+/// The sort key comes first and the name breaks ties, which makes the order
+/// total since the cards are grouped by name.
+fn keyset_terms(sort: Option<CardSort>, order: SortOrder) -> Vec<(&'static str, bool)> {
+    let descending = order == SortOrder::Desc;
+    match sort {
+        None => vec![("c.name", descending)],
+        Some(CardSort::Name) => vec![("c.name", descending)],
+        Some(sort) => sort
+            .keys()
+            .iter()
+            .map(|key| (*key, descending))
+            .chain([("c.name", false)])
+            .collect(),
+    }
+}
+
+/// The values of `keyset_terms` for a card, what a keyset cursor holds
+fn keyset_values(sort: Option<CardSort>, card: &Card) -> Vec<JsonValue> {
+    match sort {
+        None | Some(CardSort::Name) => vec![card.name.clone().into()],
+        Some(sort) => {
+            let mut values = sort.key_values(card);
+            values.push(card.name.clone().into());
+            values
+        }
+    }
+}
+
+/// Replaces a missing value with one that sorts after every other in the
+/// term's direction, so cards without a value come last and can still be
+/// compared to a cursor. Blobs sort after any number or text, and -inf before.
+fn nulls_last(expression: &str, descending: bool) -> String {
+    if descending {
+        format!("COALESCE({}, -9e999)", expression)
+    } else {
+        format!("COALESCE({}, x'')", expression)
+    }
+}
+
+/// `ORDER BY` terms for `keyset_terms`, reversed when paging `backward`
+fn keyset_order_by(terms: &[(&str, bool)], backward: bool) -> String {
+    terms
+        .iter()
+        .map(|(expression, descending)| {
+            let direction = if descending ^ backward { "DESC" } else { "ASC" };
+            format!("{} {}", nulls_last(expression, *descending), direction)
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Matches the cards after, or before when paging `backward`, the cursor's
+/// values, bound as `:key0`, `:key1`...
+fn keyset_condition(terms: &[(&str, bool)], backward: bool) -> String {
+    terms
+        .iter()
+        .enumerate()
+        .rev()
+        .fold(String::new(), |after, (i, (expression, descending))| {
+            let term = nulls_last(expression, *descending);
+            let value = nulls_last(&format!(":key{}", i), *descending);
+            let op = if descending ^ backward { "<" } else { ">" };
+            if after.is_empty() {
+                format!("{} {} {}", term, op, value)
+            } else {
+                format!(
+                    "({} {} {} OR ({} = {} AND {}))",
+                    term, op, value, term, value, after
+                )
+            }
+        })
+}
+
+/// Which page of a card search to return, and in what order
+#[derive(Debug, Clone)]
+pub struct CardPage {
+    pub cursor: CardCursor,
+    pub page_size: u32,
+    /// `None` orders by relevance, or by name when there is no search
+    pub sort: Option<CardSort>,
+    pub order: SortOrder,
+}
+
+/// Where a page of a search starts. Searches ordered by relevance are paged
+/// by offset, the rest by their sort key, which keeps the pages in place when
+/// cards are added or removed. It is handed to clients as an opaque string,
+/// so what it holds can change without changing the api.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardCursor {
+    Offset(u32),
+    /// The cards after the one with these `keyset_values`
+    After(Vec<JsonValue>),
+    /// The cards before it, for the previous page
+    Before(Vec<JsonValue>),
+}
+
+impl CardCursor {
+    /// Hex encoded json, so it can go in a url as is
+    pub fn encode(&self) -> String {
+        serde_json::to_vec(self)
+            .unwrap_or_default()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn decode(cursor: &str) -> Result<CardCursor> {
+        let invalid = || InvalidCursor(format!("Invalid cursor {}", cursor));
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| {
+                cursor
+                    .get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        Ok(serde_json::from_slice(&bytes).map_err(|_| invalid())?)
+    }
+}
+
+/// A cursor that can't be decoded, or that is from a differently ordered search
+#[derive(Debug)]
+pub struct InvalidCursor(pub String);
+
+impl fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCursor {}
+
+/// A page of a card search
+#[derive(Debug)]
+pub struct CardResults {
+    pub cards: Vec<Card>,
+    /// Number of cards the whole search matches
+    pub total: u64,
+    /// Whether `total` is an estimate rather than an exact count
+    pub estimated: bool,
+    /// Whether the search matches more cards than the `total` it pages
+    /// through, as sorted semantic and hybrid searches only sort their best
+    pub capped: bool,
+    /// `None` on the last page
+    pub next: Option<CardCursor>,
+    /// `None` on the first page
+    pub prev: Option<CardCursor>,
+}
+
+impl CardResults {
+    /// A page of an offset paged search, from `cards` fetched with one card
+    /// past the page to tell whether there is a next one
+    fn offset_page(
+        mut cards: Vec<Card>,
+        total: u64,
+        estimated: bool,
+        offset: u32,
+        page_size: u32,
+    ) -> CardResults {
+        let more = cards.len() > page_size as usize;
+        cards.truncate(page_size as usize);
+        CardResults {
+            cards,
+            total,
+            estimated,
+            capped: false,
+            next: offset
+                .checked_add(page_size)
+                .filter(|_| more)
+                .map(CardCursor::Offset),
+            prev: (offset > 0).then(|| CardCursor::Offset(offset.saturating_sub(page_size))),
+        }
+    }
+}
+
+/// sqlite-vec refuses knn queries for more neighbours than this
+const KNN_MAX_K: u32 = 4096;
+
+/// The offset one card past an offset paged page, which tells whether there is
+/// another page. Offsets come from clients, so ones that overflow are refused.
+fn page_end(offset: u32, page_size: u32) -> Result<u32> {
+    offset
+        .checked_add(page_size)
+        .and_then(|end| end.checked_add(1))
+        .ok_or_else(|| InvalidCursor(format!("Offset {} is out of range", offset)).into())
+}
+
+/// Semantic searches compare `query_embedding`, the embedded `search_query`,
+/// with the card embeddings
 pub fn search_cards(
     conn: &Connection,
     search_query: &str,
-    page: u32,
-    page_size: u32,
+    page: &CardPage,
     search_type: CardSearchType,
-) -> Result<Vec<Card>> {
-    let offset = (page - 1) * page_size;
-    let limit = page_size;
+    filters: &CardFilters,
+    query_embedding: Option<&QueryEmbedding>,
+) -> Result<CardResults> {
+    // Full text searches match a finite set of cards, which is sorted as a
+    // whole like any other, but semantic ones match every embedded card
+    let unbounded = !search_query.is_empty()
+        && matches!(
+            search_type,
+            CardSearchType::Semantic | CardSearchType::Hybrid
+        );
+    if let (true, Some(sort)) = (unbounded, page.sort) {
+        return search_cards_sorted(
+            conn,
+            search_query,
+            page,
+            sort,
+            search_type,
+            filters,
+            query_embedding,
+        );
+    }
+    if search_type == CardSearchType::Hybrid && !search_query.is_empty() {
+        return search_cards_hybrid(conn, search_query, page, filters, query_embedding);
+    }
+    // Without a search every card matches, in name order
+    let search_type = if search_query.is_empty() {
+        None
+    } else {
+        Some(search_type)
+    };
 
-    const PAGINATION_STMTS: &str = "
-    GROUP BY c.name
-    LIMIT :limit
-    OFFSET :offset
-    ";
+    // Relevance depends on the search rather than on the card, so only
+    // searches in sort key order can be paged by it
+    let keyset = match search_type {
+        None | Some(CardSearchType::Like | CardSearchType::Hybrid) => true,
+        Some(CardSearchType::FullText) => page.sort.is_some(),
+        Some(CardSearchType::Semantic) => false,
+    };
+    let terms = keyset_terms(page.sort, page.order);
+    let (offset, cursor_values, backward) = match &page.cursor {
+        CardCursor::Offset(offset) => (*offset, None, false),
+        CardCursor::After(values) if keyset && values.len() == terms.len() => {
+            (0, Some(values), false)
+        }
+        CardCursor::Before(values) if keyset && values.len() == terms.len() => {
+            (0, Some(values), true)
+        }
+        _ => {
+            return Err(InvalidCursor(
+                "The cursor is from a differently ordered search".to_string(),
+            )
+            .into())
+        }
+    };
+    let end = page_end(offset, page.page_size)?;
+    let key_params = cursor_values
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, value)| Ok((format!(":key{}", i), json_to_sql(value)?)))
+        .collect::<Result<Vec<(String, Value)>>>()?;
 
-    let stmt_str = if search_query.is_empty() {
-        String::from(format!("{}{};", SELECT_ALL_CARDS, PAGINATION_STMTS))
-    } else {
-        match search_type {
-            CardSearchType::Semantic => String::from(SELECT_PAGINATED_SEMANTIC_SEARCH),
-            CardSearchType::Like => String::from(format!(
-                "{} WHERE c.name LIKE :search COLLATE NOCASE or c.oracle_text LIKE :search COLLATE NOCASE or c.flavor_text LIKE :search COLLATE NOCASE {};",
-                SELECT_ALL_CARDS, PAGINATION_STMTS
-            )),
+    // Only quotes, nothing to match
+    if search_type == Some(CardSearchType::FullText) && fts_query(search_query).is_empty() {
+        return Ok(CardResults::offset_page(
+            Vec::new(),
+            0,
+            false,
+            offset,
+            page.page_size,
+        ));
+    }
+
+    let search = match search_type {
+        None => None,
+        Some(CardSearchType::Semantic) => {
+            let query_embedding =
+                query_embedding.ok_or(anyhow!("Semantic search needs a query embedding"))?;
+            check_embedding_index(conn, &query_embedding.model_name, VecTable::Cards)?;
+            Some(format!("{:?}", query_embedding.embedding))
         }
+        Some(CardSearchType::FullText) => Some(fts_query(search_query)),
+        Some(CardSearchType::Like | CardSearchType::Hybrid) => Some(format!("%{}%", search_query)),
     };
 
-    let mut stmt = conn
-        .prepare(&stmt_str)
-        .context("Failed to prepare card search")?;
-    let mut rows = if search_query.is_empty() {
-        stmt.query(named_params! {":limit": &limit.to_string(), ":offset": &offset.to_string()})
-            .context("Failed to execute prepared pagination no search")?
-    } else {
-        match search_type {
-            CardSearchType::Semantic => {
-                let embed_model = init().context("Failed to init fastembed")?;
-                let embedded_search = string_to_embedding(&search_query, &embed_model)
-                    .context("Failed to convert search to embedding")?;
-                stmt.query(named_params! {
-                    ":search": format!("{:?}", embedded_search),
-                    ":limit": &limit.to_string(),
-                    ":offset": &offset.to_string()
-                })
-                .context("Failed to execute prepared search")?
+    let (filters_sql, query_params) = filters.sql();
+    // One card past the page tells whether there is another page
+    let limit = page.page_size.saturating_add(1);
+    let mut stmt_params: Vec<(&str, &dyn ToSql)> = vec![
+        (":limit", &limit),
+        (":offset", &offset),
+        (":format", &filters.format),
+        (":min_price", &filters.min_price),
+        (":max_price", &filters.max_price),
+        (":colors", &filters.colors),
+        (":identity", &filters.identity),
+        (":produces", &filters.produces),
+        (":keyword", &filters.keyword),
+        (":set", &filters.set),
+        (":rarity", &filters.rarity),
+        (":cmc_min", &filters.cmc_min),
+        (":cmc_max", &filters.cmc_max),
+        (":type_line", &filters.type_line),
+        (":artist", &filters.artist),
+    ];
+    if let Some(search) = &search {
+        stmt_params.push((":search", search));
+    }
+    for (name, value) in query_params.iter().chain(&key_params) {
+        stmt_params.push((name, value));
+    }
+
+    // The cards the search matches, before any grouping or ordering
+    let (matches_from, matches_where) = match search_type {
+        None => ("FROM cards as c", filters_sql.clone()),
+        // Every embedded card is a match, just a more or less distant one
+        Some(CardSearchType::Semantic) => (
+            "FROM cards as c",
+            format!("c.embedding_hash IS NOT NULL AND {}", filters_sql),
+        ),
+        Some(CardSearchType::FullText) => (
+            "FROM card_fts JOIN cards as c ON c.rowid = card_fts.rowid",
+            format!("card_fts MATCH :search AND {}", filters_sql),
+        ),
+        Some(CardSearchType::Like | CardSearchType::Hybrid) => (
+            "FROM cards as c",
+            format!(
+                "(c.name LIKE :search COLLATE NOCASE or c.oracle_text LIKE :search COLLATE NOCASE or c.flavor_text LIKE :search COLLATE NOCASE or {}) AND {}",
+                FACES_LIKE_SEARCH, filters_sql
+            ),
+        ),
+    };
+    let (total, matching_rows): (u64, u64) = {
+        let mut stmt = query_with_params(
+            conn,
+            &format!(
+                "SELECT COUNT(DISTINCT c.name), COUNT(*) {} WHERE {};",
+                matches_from, matches_where
+            ),
+            &stmt_params,
+        )
+        .context("Failed to prepare card search count")?;
+        let mut rows = stmt.raw_query();
+        let row = rows
+            .next()?
+            .ok_or(anyhow!("Card search count returned no row"))?;
+        (row.get(0)?, row.get(1)?)
+    };
+
+    // Applied after grouping, so a card is compared to the cursor by the same
+    // print it is ordered by
+    let having = match cursor_values {
+        Some(_) => format!("HAVING {}", keyset_condition(&terms, backward)),
+        None => String::new(),
+    };
+    // Ranked searches are ordered by their rank unless sorted otherwise
+    let order_by = |rank: &str| {
+        if keyset {
+            keyset_order_by(&terms, backward)
+        } else {
+            format!("{}, c.name", rank)
+        }
+    };
+    let pagination = |rank: &str| {
+        format!(
+            "{}
+    ORDER BY {}
+    LIMIT :limit
+    OFFSET :offset",
+            having,
+            order_by(rank)
+        )
+    };
+
+    let mut cards = match search_type {
+        Some(CardSearchType::Semantic) => {
+            // Prints of a card share a name, and often an embedding, so the
+            // nearest neighbours are deepened until they fill the page once grouped
+            let mut k = end.saturating_mul(2);
+            loop {
+                let knn = k <= KNN_MAX_K;
+                let sql = paginated_semantic_search_sql(&filters_sql, &pagination("distance"), knn);
+                let mut params = stmt_params.clone();
+                params.push((":k", &k));
+                let cards = query_cards(conn, &sql, &params, search_type)?;
+                if !knn || cards.len() as u32 == limit || k as u64 >= matching_rows {
+                    break cards;
+                }
+                k = k.saturating_mul(4);
             }
-            CardSearchType::Like => stmt
-                .query(named_params! {
-                    ":search": format!("%{}%", &search_query.to_string()),
-                    ":limit": &limit.to_string(),
-                    ":offset": &offset.to_string()
-                })
-                .context("Failed to execute prepared search")?,
         }
+        Some(CardSearchType::FullText) => query_cards(
+            conn,
+            &paginated_full_text_search_sql(&filters_sql, &pagination("rank")),
+            &stmt_params,
+            search_type,
+        )?,
+        _ => query_cards(
+            conn,
+            &format!(
+                "SELECT {} {} {} WHERE {} GROUP BY c.name {};",
+                CARD_COLUMNS,
+                matches_from,
+                CARD_IMAGE_JOINS,
+                matches_where,
+                pagination("c.name")
+            ),
+            &stmt_params,
+            search_type,
+        )?,
+    };
+
+    if !keyset {
+        return Ok(CardResults::offset_page(
+            cards,
+            total,
+            false,
+            offset,
+            page.page_size,
+        ));
+    }
+
+    let more = cards.len() > page.page_size as usize;
+    cards.truncate(page.page_size as usize);
+    if backward {
+        cards.reverse();
+    }
+    let after_last = cards
+        .last()
+        .map(|card| CardCursor::After(keyset_values(page.sort, card)));
+    let before_first = cards
+        .first()
+        .map(|card| CardCursor::Before(keyset_values(page.sort, card)));
+    // The page a cursor came from is on the other side of it
+    let (next, prev) = match &page.cursor {
+        CardCursor::Before(_) => (after_last, before_first.filter(|_| more)),
+        CardCursor::After(_) => (after_last.filter(|_| more), before_first),
+        CardCursor::Offset(offset) => (
+            after_last.filter(|_| more),
+            before_first.filter(|_| *offset > 0),
+        ),
     };
+    Ok(CardResults {
+        cards,
+        total,
+        estimated: false,
+        capped: false,
+        next,
+        prev,
+    })
+}
+
+fn json_to_sql(value: &JsonValue) -> Result<Value> {
+    Ok(match value {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(b) => Value::Integer(*b as i64),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::String(s) => Value::Text(s.clone()),
+        _ => return Err(InvalidCursor(format!("Invalid cursor value {}", value)).into()),
+    })
+}
+
+/// Prepares `sql` with the parameters it uses out of `params`, as rusqlite
+/// refuses to bind names the statement doesn't have
+fn query_with_params<'conn>(
+    conn: &'conn Connection,
+    sql: &str,
+    params: &[(&str, &dyn ToSql)],
+) -> Result<rusqlite::Statement<'conn>> {
+    let mut stmt = conn.prepare(sql)?;
+    for (name, value) in params {
+        if let Some(index) = stmt.parameter_index(name)? {
+            stmt.raw_bind_parameter(index, value)?;
+        }
+    }
+    Ok(stmt)
+}
+
+fn query_cards(
+    conn: &Connection,
+    sql: &str,
+    params: &[(&str, &dyn ToSql)],
+    search_type: Option<CardSearchType>,
+) -> Result<Vec<Card>> {
+    let mut stmt = query_with_params(conn, sql, params).context("Failed to prepare card search")?;
+    let mut rows = stmt.raw_query();
 
     let mut results = Vec::new();
     while let Some(row) = rows.next()? {
-        let card = Card {
-            id: row.get(0)?,
-            oracle_id: row.get(1)?,
-            name: row.get(2)?,
-            lang: row.get(3)?,
-            released_at: row.get(4)?,
-            mana_cost: row.get(5)?,
-            cmc: row.get(6)?,
-            type_line: row.get(7)?,
-            oracle_text: row.get(8)?,
-            power: row.get(9)?,
-            toughness: row.get(10)?,
-            rarity: row.get(11)?,
-            flavor_text: row.get(12)?,
-            artist: row.get(13)?,
-            set_code: row.get(14)?,
-            collector_number: row.get(15)?,
-            digital: row.get(16)?,
-            image_url: row.get(17)?,
-        };
-
+        let mut card = card_from_row(row)?;
+        // The search's own columns come after the card columns
+        match search_type {
+            Some(CardSearchType::Semantic) => card.distance = row.get(23)?,
+            Some(CardSearchType::FullText) => {
+                card.snippet = row.get(22)?;
+                card.bm25 = row.get(23)?;
+            }
+            _ => {}
+        }
         results.push(card);
     }
 
+    attach_card_details(conn, &mut results)?;
     Ok(results)
 }
+
+/// How many of the best matches of a semantic or hybrid search a sort reorders
+const SORTED_SEARCH_DEPTH: u32 = 500;
+
+/// A sort over a semantic or hybrid search reorders its `SORTED_SEARCH_DEPTH`
+/// best matches, the same ones whatever the page, so paging through the
+/// sorted matches neither repeats nor skips cards. Sorting every match instead
+/// would ignore the search, since every embedded card is a match.
+fn search_cards_sorted(
+    conn: &Connection,
+    search_query: &str,
+    page: &CardPage,
+    sort: CardSort,
+    search_type: CardSearchType,
+    filters: &CardFilters,
+    query_embedding: Option<&QueryEmbedding>,
+) -> Result<CardResults> {
+    let offset = match page.cursor {
+        CardCursor::Offset(offset) => offset,
+        _ => {
+            return Err(
+                InvalidCursor("Sorted ranked searches are paged by offset".to_string()).into(),
+            )
+        }
+    };
+    let best = CardPage {
+        cursor: CardCursor::Offset(0),
+        page_size: SORTED_SEARCH_DEPTH,
+        sort: None,
+        order: SortOrder::Asc,
+    };
+    let best = search_cards(
+        conn,
+        search_query,
+        &best,
+        search_type,
+        filters,
+        query_embedding,
+    )?;
+    let mut cards = best.cards;
+    cards.sort_by(|a, b| sort.compare(a, b, page.order));
+
+    // Only the best matches are paged through, so they are the total
+    let total = cards.len() as u64;
+    let cards = cards
+        .into_iter()
+        .skip(offset as usize)
+        .take(page.page_size as usize + 1)
+        .collect();
+    Ok(CardResults {
+        capped: best.total > total,
+        ..CardResults::offset_page(cards, total, false, offset, page.page_size)
+    })
+}
+
+/// Ranks the cards by reciprocal rank fusion of their `FullText` and
+/// `Semantic` ranks. Every card up to the requested page is fetched from both rankings,
+/// since a card's fused rank depends on its rank in each.
+fn search_cards_hybrid(
+    conn: &Connection,
+    search_query: &str,
+    page: &CardPage,
+    filters: &CardFilters,
+    query_embedding: Option<&QueryEmbedding>,
+) -> Result<CardResults> {
+    let offset = match page.cursor {
+        CardCursor::Offset(offset) => offset,
+        _ => return Err(InvalidCursor("Hybrid searches are paged by offset".to_string()).into()),
+    };
+    // One card past the page tells whether there is another page
+    let depth = CardPage {
+        cursor: CardCursor::Offset(0),
+        page_size: page_end(offset, page.page_size)?,
+        sort: None,
+        order: SortOrder::Asc,
+    };
+    let rankings = [
+        search_cards(
+            conn,
+            search_query,
+            &depth,
+            CardSearchType::FullText,
+            filters,
+            None,
+        )?,
+        search_cards(
+            conn,
+            search_query,
+            &depth,
+            CardSearchType::Semantic,
+            filters,
+            query_embedding,
+        )?,
+    ];
+
+    // The matches of both overlap by an unknown amount
+    let total = rankings.iter().map(|r| r.total).max().unwrap_or(0);
+
+    // Both searches group by name, so a card is the same in both by name
+    let mut fused: BTreeMap<String, Card> = BTreeMap::new();
+    for ranking in rankings {
+        for (rank, card) in ranking.cards.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f64 + 1.0);
+            match fused.get_mut(&card.name) {
+                Some(existing) => {
+                    existing.score = existing.score.map(|s| s + score);
+                    existing.distance = existing.distance.or(card.distance);
+                    existing.snippet = existing.snippet.take().or(card.snippet);
+                    existing.bm25 = existing.bm25.or(card.bm25);
+                }
+                None => {
+                    fused.insert(
+                        card.name.clone(),
+                        Card {
+                            score: Some(score),
+                            ..card
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    let mut results: Vec<Card> = fused.into_values().collect();
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    let cards = results
+        .into_iter()
+        .skip(offset as usize)
+        .take(page.page_size as usize + 1)
+        .collect();
+    Ok(CardResults::offset_page(
+        cards,
+        total,
+        true,
+        offset,
+        page.page_size,
+    ))
+}
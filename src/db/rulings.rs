@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use rusqlite::{named_params, params, Connection, Row, Statement};
+use serde::{Deserialize, Serialize};
+
+use super::vectors::{check_embedding_index, VecTable};
+use super::{card_exists, get_card_oracle_id, KNN_MAX_K};
+use crate::embedings::QueryEmbedding;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ruling {
+    pub oracle_id: String,
+    pub source: String,
+    pub published_at: String,
+    pub comment: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RulingSearchResult {
+    /// Name of a card the ruling applies to
+    pub card_name: Option<String>,
+    pub distance: f64,
+    #[serde(flatten)]
+    pub ruling: Ruling,
+}
+
+pub fn prep_insert_ruling(conn: &Connection) -> rusqlite::Result<Statement<'_>> {
+    conn.prepare(
+        "INSERT OR IGNORE INTO rulings (oracle_id, source, published_at, comment)
+        VALUES (?, ?, ?, ?);",
+    )
+}
+
+/// Temp table of the rulings seen during an ingest, used to find rulings that
+/// were removed from the scryfall data
+pub fn init_seen_rulings(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TEMP TABLE IF NOT EXISTS seen_rulings (id INTEGER PRIMARY KEY);",
+        [],
+    )?;
+    conn.execute("DELETE FROM temp.seen_rulings;", [])?;
+    Ok(())
+}
+
+pub fn prep_insert_seen_ruling(conn: &Connection) -> rusqlite::Result<Statement<'_>> {
+    conn.prepare(
+        "INSERT OR IGNORE INTO temp.seen_rulings (id)
+        SELECT id FROM rulings
+        WHERE oracle_id = ? AND source = ? AND published_at = ? AND comment = ?;",
+    )
+}
+
+/// Deletes every ruling, and its embedding, that was not seen during the
+/// ingest. Returns the number of removed rulings.
+pub fn delete_unseen_rulings(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM ruling_vecs WHERE rowid NOT IN (SELECT id FROM temp.seen_rulings);",
+        [],
+    )?;
+    let removed = conn.execute(
+        "DELETE FROM rulings WHERE id NOT IN (SELECT id FROM temp.seen_rulings);",
+        [],
+    )?;
+    Ok(removed)
+}
+
+pub fn prep_insert_ruling_vec(conn: &Connection) -> rusqlite::Result<Statement<'_>> {
+    conn.prepare("INSERT INTO ruling_vecs (rowid, embedding) VALUES (?, ?);")
+}
+
+pub fn prep_set_ruling_embedded(conn: &Connection) -> rusqlite::Result<Statement<'_>> {
+    conn.prepare("UPDATE rulings SET embedded = 1 WHERE id = ?;")
+}
+
+/// Rulings are never re-embedded, so one whose card hasn't been imported yet
+/// waits for a later run rather than being embedded without the card's name
+const UNEMBEDDED_RULINGS_WHERE: &str = "r.embedded = 0
+    AND EXISTS (SELECT 1 FROM cards c WHERE c.oracle_id = r.oracle_id)";
+
+/// Rulings the next embedding run will embed
+pub fn count_unembedded_rulings(conn: &Connection) -> Result<u64> {
+    let count = conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM rulings r WHERE {};",
+            UNEMBEDDED_RULINGS_WHERE
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Rulings still waiting for their card to be imported
+pub fn count_rulings_without_card(conn: &Connection) -> Result<u64> {
+    let count = conn.query_row(
+        "SELECT COUNT(*) FROM rulings r
+        WHERE NOT EXISTS (SELECT 1 FROM cards c WHERE c.oracle_id = r.oracle_id);",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Selects a page of rulings without an embedding, after a given id, with the
+/// text their embedding is generated from
+pub fn prep_get_ruling_embedding_text_page(conn: &Connection) -> rusqlite::Result<Statement<'_>> {
+    conn.prepare(&format!(
+        "SELECT
+            r.id,
+            (SELECT c.name FROM cards c WHERE c.oracle_id = r.oracle_id LIMIT 1),
+            r.comment
+        FROM rulings r
+        WHERE {} AND r.id > ?
+        ORDER BY r.id
+        LIMIT ?;",
+        UNEMBEDDED_RULINGS_WHERE
+    ))
+}
+
+/// The text a ruling's embedding is generated from
+#[derive(Debug, Clone)]
+pub struct RulingEmbeddingText {
+    pub id: i64,
+    pub text: String,
+}
+
+impl RulingEmbeddingText {
+    pub fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let name: String = row.get(1)?;
+        let comment: String = row.get(2)?;
+
+        Ok(RulingEmbeddingText {
+            id: row.get(0)?,
+            text: format!("<name>{:?}<ruling>{:?}", &name, &comment),
+        })
+    }
+}
+
+/// Rulings of a card, oldest first. `None` if there is no such card.
+pub fn get_card_rulings(conn: &Connection, card_id: &str) -> Result<Option<Vec<Ruling>>> {
+    if !card_exists(conn, card_id)? {
+        return Ok(None);
+    }
+    // Reversible cards have no top level oracle id, and no rulings of their own
+    let Some(oracle_id) = get_card_oracle_id(conn, card_id)? else {
+        return Ok(Some(Vec::new()));
+    };
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT oracle_id, source, published_at, comment
+            FROM rulings
+            WHERE oracle_id = ?
+            ORDER BY published_at, id;",
+        )
+        .context("Failed to prepare card rulings")?;
+    let rulings = stmt
+        .query_map(params![oracle_id], |row| {
+            Ok(Ruling {
+                oracle_id: row.get(0)?,
+                source: row.get(1)?,
+                published_at: row.get(2)?,
+                comment: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<Ruling>>>()?;
+
+    Ok(Some(rulings))
+}
+
+/// Semantic search over the ruling embeddings. A `knn` search only ranks the
+/// `:k` nearest embeddings, which has to reach past the page's offset,
+/// otherwise the distance to every embedding is computed, for pages past what
+/// knn can reach.
+pub fn paginated_rulings_semantic_search_sql(knn: bool) -> String {
+    let nearest = if knn {
+        "
+        SELECT rowid, distance
+        FROM ruling_vecs
+        WHERE embedding match :search
+        and k = :k"
+    } else {
+        "
+        SELECT rowid, vec_distance_l2(embedding, :search) AS distance
+        FROM ruling_vecs"
+    };
+    format!(
+        "
+    WITH nearest AS ({}
+    )
+    SELECT r.oracle_id, r.source, r.published_at, r.comment,
+        (SELECT c.name FROM cards c WHERE c.oracle_id = r.oracle_id LIMIT 1),
+        nearest.distance
+    FROM nearest
+    JOIN rulings as r
+    ON r.id = nearest.rowid
+    ORDER BY nearest.distance
+    LIMIT :limit
+    OFFSET :offset;
+    ",
+        nearest
+    )
+}
+
+pub fn search_rulings(
+    conn: &Connection,
+    query_embedding: &QueryEmbedding,
+    page: u32,
+    page_size: u32,
+) -> Result<Vec<RulingSearchResult>> {
+    // Pages before the first are the first
+    let offset = page.saturating_sub(1).saturating_mul(page_size);
+    let limit = page_size;
+    // Every ruling up to the end of the page has to be among the nearest
+    let k = offset.saturating_add(limit);
+    let knn = k <= KNN_MAX_K;
+
+    check_embedding_index(conn, &query_embedding.model_name, VecTable::Rulings)?;
+
+    let sql = paginated_rulings_semantic_search_sql(knn);
+    let mut stmt = conn
+        .prepare(&sql)
+        .context("Failed to prepare rulings search")?;
+    let search = format!("{:?}", query_embedding.embedding);
+    let mut stmt_params = named_params! {
+        ":search": search,
+        ":limit": limit,
+        ":offset": offset,
+    }
+    .to_vec();
+    if knn {
+        stmt_params.push((":k", &k));
+    }
+    let results = stmt
+        .query_map(stmt_params.as_slice(), |row| {
+            Ok(RulingSearchResult {
+                ruling: Ruling {
+                    oracle_id: row.get(0)?,
+                    source: row.get(1)?,
+                    published_at: row.get(2)?,
+                    comment: row.get(3)?,
+                },
+                card_name: row.get(4)?,
+                distance: row.get(5)?,
+            })
+        })
+        .context("Failed to execute prepared rulings search")?
+        .collect::<rusqlite::Result<Vec<RulingSearchResult>>>()?;
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::db::init_conn;
+
+    #[test]
+    fn waits_for_a_rulings_card_before_embedding_it() {
+        let settings = Settings {
+            db_path: String::from(":memory:"),
+            ..Settings::default()
+        };
+        let conn = init_conn(&settings).unwrap();
+        let mut insert_ruling = prep_insert_ruling(&conn).unwrap();
+        insert_ruling
+            .execute(params!["oracle-1", "wotc", "2024-01-01", "First"])
+            .unwrap();
+        insert_ruling
+            .execute(params!["oracle-2", "wotc", "2024-01-01", "Second"])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO cards (id, oracle_id, name) VALUES ('card-1', 'oracle-1', 'Opt');",
+            [],
+        )
+        .unwrap();
+
+        let texts: Vec<RulingEmbeddingText> = prep_get_ruling_embedding_text_page(&conn)
+            .unwrap()
+            .query_map(params![0, 100], RulingEmbeddingText::from_row)
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(texts.len(), 1);
+        assert_eq!(texts[0].text, "<name>\"Opt\"<ruling>\"First\"");
+        assert_eq!(count_unembedded_rulings(&conn).unwrap(), 1);
+        assert_eq!(count_rulings_without_card(&conn).unwrap(), 1);
+    }
+}
@@ -1,6 +1,155 @@
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::{seq::SliceRandom, thread_rng};
-use rusqlite::{Connection, Result, Statement};
+use rusqlite::{Connection, Result, Row, Statement};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use super::{get_metadata, set_metadata, CARD_COLUMNS, CARD_IMAGE_JOINS};
+use crate::embedings::{model_dim, ModelMismatch};
+
+/// Bump when the format of `CardEmbeddingText` changes
+pub const EMBEDDING_TEXT_VERSION: &str = "1";
+
+/// What the vectors in `card_vecs` and `ruling_vecs` were generated with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingIndex {
+    pub model: String,
+    pub dim: usize,
+    /// Only set once every card has been embedded with this version
+    pub text_version: Option<String>,
+}
+
+pub fn get_embedding_index(conn: &Connection) -> anyhow::Result<Option<EmbeddingIndex>> {
+    let model = get_metadata(conn, "embedding_model")?;
+    let dim = get_metadata(conn, "embedding_dim")?;
+    match (model, dim) {
+        (Some(model), Some(dim)) => Ok(Some(EmbeddingIndex {
+            model,
+            dim: dim.parse()?,
+            text_version: get_metadata(conn, "embedding_text_version")?,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// The vec tables, each searchable once every row of it has been embedded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VecTable {
+    Cards,
+    Rulings,
+}
+
+impl VecTable {
+    /// The metadata key set once the table is fully embedded
+    fn complete_key(&self) -> &'static str {
+        match self {
+            VecTable::Cards => "card_vecs_complete",
+            VecTable::Rulings => "ruling_vecs_complete",
+        }
+    }
+
+    /// The binary that fills the table
+    fn builder(&self) -> &'static str {
+        match self {
+            VecTable::Cards => "scryfall_convert",
+            VecTable::Rulings => "rulings_convert",
+        }
+    }
+}
+
+/// A vec table that is empty or partly filled, because it was just recreated
+/// or its ingest was interrupted, and would quietly miss matches
+#[derive(Debug)]
+pub struct IncompleteEmbeddingIndex(pub VecTable);
+
+impl fmt::Display for IncompleteEmbeddingIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The embedding index is incomplete. Run {} to finish it",
+            self.0.builder()
+        )
+    }
+}
+
+impl std::error::Error for IncompleteEmbeddingIndex {}
+
+/// Fails with `ModelMismatch` if the index was built with a model other than
+/// `model`, or with `IncompleteEmbeddingIndex` if `table` isn't fully
+/// embedded with the current embedding text
+pub fn check_embedding_index(
+    conn: &Connection,
+    model: &str,
+    table: VecTable,
+) -> anyhow::Result<()> {
+    check_embedding_model(conn, model)?;
+    let text_version = get_metadata(conn, "embedding_text_version")?;
+    let stale = table == VecTable::Cards && text_version.as_deref() != Some(EMBEDDING_TEXT_VERSION);
+    if stale || get_metadata(conn, table.complete_key())?.is_none() {
+        return Err(IncompleteEmbeddingIndex(table).into());
+    }
+    Ok(())
+}
+
+/// Records that every row of `table` has been embedded
+pub fn set_embedding_index_complete(conn: &Connection, table: VecTable) -> anyhow::Result<()> {
+    set_metadata(conn, table.complete_key(), "1")
+}
+
+/// Fails with `ModelMismatch` if the index was built with a model other than
+/// `model`, however complete it is
+pub fn check_embedding_model(conn: &Connection, model: &str) -> anyhow::Result<()> {
+    let index = get_embedding_index(conn)?;
+    if index.as_ref().map(|i| i.model.as_str()) != Some(model) {
+        return Err(ModelMismatch {
+            configured: model.to_string(),
+            indexed: index.map(|i| i.model),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Makes the vec tables fit `model`. If they were built with another model
+/// they are recreated, empty, with the model's dimension and every card and
+/// ruling is marked as needing an embedding. Returns whether they were recreated.
+pub fn prepare_embedding_index(conn: &Connection, model: &str) -> anyhow::Result<bool> {
+    let dim = model_dim(model)?;
+    if let Some(index) = get_embedding_index(conn)? {
+        if index.model == model && index.dim == dim {
+            return Ok(false);
+        }
+    }
+
+    conn.execute_batch(&format!(
+        "
+        BEGIN;
+        DROP TABLE IF EXISTS card_vecs;
+        DROP TABLE IF EXISTS ruling_vecs;
+        CREATE VIRTUAL TABLE card_vecs using vec0 (
+            embedding float[{dim}]
+        );
+        CREATE VIRTUAL TABLE ruling_vecs using vec0 (
+            embedding float[{dim}]
+        );
+        UPDATE cards SET embedding_hash = NULL;
+        UPDATE rulings SET embedded = 0;
+        DELETE FROM metadata WHERE key IN (
+            'embedding_text_version', 'card_vecs_complete', 'ruling_vecs_complete'
+        );
+        COMMIT;
+        ",
+    ))?;
+    set_metadata(conn, "embedding_model", model)?;
+    set_metadata(conn, "embedding_dim", &dim.to_string())?;
+    Ok(true)
+}
+
+/// Records that every card embedding was generated from the current text format
+pub fn set_embedding_text_version(conn: &Connection) -> anyhow::Result<()> {
+    set_metadata(conn, "embedding_text_version", EMBEDDING_TEXT_VERSION)
+}
 
 pub fn get_vec_version_stmt(conn: &Connection) -> Result<Statement> {
     conn.prepare("SELECT vec_version();")
@@ -14,25 +163,194 @@ pub fn prep_get_all_embeddings(conn: &Connection) -> Result<Statement> {
     conn.prepare("SELECT embedding, rowid FROM card_vecs;")
 }
 
-pub const SELECT_PAGINATED_SEMANTIC_SEARCH: &str = "
-    SELECT c.id, c.oracle_id, c.name, c.lang, 
-        c.released_at, c.mana_cost, c.cmc, 
-        c.type_line, c.oracle_text, c.power, 
-        c.toughness, c.rarity, c.flavor_text, 
-        c.artist, c.set_code, c.collector_number, 
-        c.digital, iu.normal, cv.rowid, cv.distance
-    FROM card_vecs as cv
+pub fn prep_delete_card_vec(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare("DELETE FROM card_vecs WHERE rowid = ?;")
+}
+
+pub fn prep_update_card_embedding_hash(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare("UPDATE cards SET embedding_hash = ? WHERE rowid = ?;")
+}
+
+/// Selects the cards matching `condition`, with the text their embedding is
+/// generated from. Multi-faced cards have no top level text, so their faces
+/// are joined together instead.
+fn card_embedding_text_sql(condition: &str) -> String {
+    format!(
+        "SELECT
+            c.rowid,
+            c.name,
+            COALESCE(c.power, {}, ''),
+            COALESCE(c.toughness, {}, ''),
+            COALESCE(c.mana_cost, {}, ''),
+            COALESCE(c.type_line, ''),
+            COALESCE(c.oracle_text, {}, ''),
+            COALESCE(c.flavor_text, {}, ''),
+            c.embedding_hash
+        FROM cards c
+        {}",
+        faces_text("power"),
+        faces_text("toughness"),
+        faces_text("mana_cost"),
+        faces_text("oracle_text"),
+        faces_text("flavor_text"),
+        condition
+    )
+}
+
+/// Selects a page of cards, after a given rowid, with their embedding text
+pub fn prep_get_card_embedding_text_page(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare(&card_embedding_text_sql(
+        "WHERE c.rowid > ? ORDER BY c.rowid LIMIT ?;",
+    ))
+}
+
+/// Selects a random sample of cards with their embedding text
+pub fn prep_get_card_embedding_text_sample(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare(&card_embedding_text_sql(
+        "WHERE c.rowid IN (SELECT rowid FROM cards ORDER BY random() LIMIT ?) ORDER BY c.rowid;",
+    ))
+}
+
+/// Selects the cards that have no embedding, with their embedding text
+pub fn prep_get_card_embedding_text_missing(conn: &Connection) -> Result<Statement<'_>> {
+    conn.prepare(&card_embedding_text_sql(
+        "WHERE c.rowid NOT IN (SELECT rowid FROM card_vecs) ORDER BY c.rowid;",
+    ))
+}
+
+pub fn get_card_embedding(conn: &Connection, rowid: i64) -> Result<Option<Vec<f32>>> {
+    let mut stmt = conn.prepare("SELECT embedding FROM card_vecs WHERE rowid = ?;")?;
+    let mut rows = stmt.query([rowid])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(bytes_to_embedding(&row.get::<_, Vec<u8>>(0)?))),
+        None => Ok(None),
+    }
+}
+
+/// Embeddings whose rowid no longer belongs to a card
+pub fn count_orphan_card_vecs(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM card_vecs WHERE rowid NOT IN (SELECT rowid FROM cards);",
+        [],
+        |row| row.get(0),
+    )
+}
+
+pub fn delete_orphan_card_vecs(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM card_vecs WHERE rowid NOT IN (SELECT rowid FROM cards);",
+        [],
+    )
+}
+
+/// Encodes an embedding the way sqlite-vec stores a float vector
+pub fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+pub fn bytes_to_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// Joins a column across all of a card's faces, e.g. `Fire text // Ice text`
+fn faces_text(column: &str) -> String {
+    format!(
+        "(SELECT group_concat(f.{}, ' // ' ORDER BY f.face_index) FROM card_faces f WHERE f.card_id = c.id)",
+        column
+    )
+}
+
+/// The text a card's embedding is generated from
+#[derive(Debug, Clone)]
+pub struct CardEmbeddingText {
+    pub rowid: i64,
+    pub text: String,
+    /// Hash of the text the currently stored embedding was generated from
+    pub embedding_hash: Option<String>,
+}
+
+impl CardEmbeddingText {
+    pub fn from_row(row: &Row) -> Result<Self> {
+        let name: String = row.get(1)?;
+        let power: String = row.get(2)?;
+        let toughness: String = row.get(3)?;
+        let mana_cost: String = row.get(4)?;
+        let type_line: String = row.get(5)?;
+        let oracle: String = row.get(6)?;
+        let flavor: String = row.get(7)?;
+
+        Ok(CardEmbeddingText {
+            rowid: row.get(0)?,
+            text: format!(
+                "<name>{:?}<power>{:?}<toughness>{:?}<cost>{:?}<type>{:?}<oracle>{:?}<flavor>{:?}",
+                &name, &power, &toughness, &mana_cost, &type_line, &oracle, &flavor,
+            ),
+            embedding_hash: row.get(8)?,
+        })
+    }
+
+    /// Hex encoded sha256 of the embedding text
+    pub fn text_hash(&self) -> String {
+        Sha256::digest(self.text.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Whether the stored embedding is missing or was generated from different text
+    pub fn needs_embedding(&self) -> bool {
+        self.embedding_hash.as_deref() != Some(self.text_hash().as_str())
+    }
+}
+
+/// Semantic search over the card embeddings, with `filters` as extra
+/// conditions on `c`, the cards table. The filters narrow down the embeddings
+/// before the nearest ones are picked, so filtering doesn't leave the page short.
+///
+/// A `knn` search only ranks the `:k` nearest embeddings, otherwise the
+/// distance to every embedding is computed, for pages past what knn can
+/// reach. `pagination` is what follows the
+/// `GROUP BY`, the `ORDER BY` and `LIMIT` of the page.
+pub fn paginated_semantic_search_sql(filters: &str, pagination: &str, knn: bool) -> String {
+    let nearest = if knn {
+        format!(
+            "
+        SELECT rowid, distance
+        FROM card_vecs
+        WHERE embedding match :search
+        and k = :k
+        and rowid IN (SELECT c.rowid FROM cards as c WHERE {})",
+            filters
+        )
+    } else {
+        format!(
+            "
+        SELECT cv.rowid, vec_distance_l2(cv.embedding, :search) AS distance
+        FROM cards as c
+        JOIN card_vecs as cv
+        ON cv.rowid = c.rowid
+        WHERE {}",
+            filters
+        )
+    };
+    format!(
+        "
+    WITH nearest AS ({}
+    )
+    SELECT {}, nearest.rowid, MIN(nearest.distance) AS distance
+    FROM nearest
     JOIN cards as c
-    ON c.rowid = cv.rowid
-    JOIN image_uris as iu
-    ON c.id = iu.card_id
-    WHERE embedding match :search
-    and k = :limit
-    GROUP BY c.name 
-    ORDER BY distance
-    LIMIT :limit
-    OFFSET :offset;
-    ";
+    ON c.rowid = nearest.rowid
+    {}
+    GROUP BY c.name
+    {};
+    ",
+        nearest, CARD_COLUMNS, CARD_IMAGE_JOINS, pagination
+    )
+}
 
 /// Calculates the Euclidean distance between two float arrays.
 ///
@@ -122,3 +440,41 @@ pub fn k_means(points: &[Point], k: usize, max_iterations: usize) -> Vec<usize>
     progress_bar.finish();
     assignments
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::db::init_conn;
+
+    const MODEL: &str = "bge-small-en-v1.5";
+
+    fn incomplete(conn: &Connection, table: VecTable) -> bool {
+        check_embedding_index(conn, MODEL, table)
+            .unwrap_err()
+            .is::<IncompleteEmbeddingIndex>()
+    }
+
+    #[test]
+    fn refuses_an_index_until_it_is_complete() {
+        let settings = Settings {
+            db_path: String::from(":memory:"),
+            ..Settings::default()
+        };
+        let conn = init_conn(&settings).unwrap();
+        prepare_embedding_index(&conn, MODEL).unwrap();
+        assert!(incomplete(&conn, VecTable::Cards));
+        assert!(incomplete(&conn, VecTable::Rulings));
+
+        // The text version alone is written before the index is complete
+        set_embedding_text_version(&conn).unwrap();
+        assert!(incomplete(&conn, VecTable::Cards));
+        set_embedding_index_complete(&conn, VecTable::Cards).unwrap();
+        check_embedding_index(&conn, MODEL, VecTable::Cards).unwrap();
+        assert!(incomplete(&conn, VecTable::Rulings));
+
+        // Text embedded in an older format is stale
+        set_metadata(&conn, "embedding_text_version", "0").unwrap();
+        assert!(incomplete(&conn, VecTable::Cards));
+    }
+}
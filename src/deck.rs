@@ -0,0 +1,45 @@
+/// A line of a decklist, e.g. `4 Lightning Bolt`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeckEntry {
+    pub count: u32,
+    pub name: String,
+}
+
+/// Parses a plain text decklist in the common `<count> <name>` format. Lines
+/// without a count are a single copy. Blank lines, `//` and `#` comments and
+/// section headers like `Sideboard` are skipped, and set codes like
+/// `(ZNR) 123` after the name are dropped.
+pub fn parse_decklist(decklist: &str) -> Vec<DeckEntry> {
+    decklist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (count, name) = match line.split_once(' ') {
+                Some((count, name)) => match count.trim_end_matches('x').parse::<u32>() {
+                    Ok(count) => (count, name),
+                    Err(_) => (1, line),
+                },
+                None => (1, line),
+            };
+            let name = match name.find(" (") {
+                Some(idx) => &name[..idx],
+                None => name,
+            }
+            .trim();
+
+            let is_header = matches!(
+                name.to_lowercase().trim_end_matches(':'),
+                "deck" | "sideboard" | "commander" | "companion" | "maybeboard"
+            );
+            if name.is_empty() || is_header {
+                return None;
+            }
+
+            Some(DeckEntry {
+                count,
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
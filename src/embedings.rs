@@ -1,8 +1,100 @@
-use anyhow::Result;
-use fastembed::{InitOptions, TextEmbedding};
+use crate::config::Settings;
+use anyhow::{anyhow, Context, Result};
+use fastembed::{
+    Embedding, EmbeddingModel, InitOptions, InitOptionsUserDefined, TextEmbedding, TokenizerFiles,
+    UserDefinedEmbeddingModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::Path,
+    sync::{mpsc::sync_channel, Arc},
+    thread,
+};
+
+/// fastembed's default model, which the vec tables were first built with
+pub const DEFAULT_MODEL: &str = "bge-small-en-v1.5";
+
+/// The models that can be chosen with the `embedding_model` setting
+const MODELS: [(&str, EmbeddingModel); 19] = [
+    ("all-minilm-l6-v2", EmbeddingModel::AllMiniLML6V2),
+    ("bge-base-en-v1.5", EmbeddingModel::BGEBaseENV15),
+    ("bge-base-en-v1.5-q", EmbeddingModel::BGEBaseENV15Q),
+    ("bge-large-en-v1.5", EmbeddingModel::BGELargeENV15),
+    ("bge-large-en-v1.5-q", EmbeddingModel::BGELargeENV15Q),
+    ("bge-small-en-v1.5", EmbeddingModel::BGESmallENV15),
+    ("bge-small-en-v1.5-q", EmbeddingModel::BGESmallENV15Q),
+    ("bge-small-zh-v1.5", EmbeddingModel::BGESmallZHV15),
+    ("nomic-embed-text-v1", EmbeddingModel::NomicEmbedTextV1),
+    ("nomic-embed-text-v1.5", EmbeddingModel::NomicEmbedTextV15),
+    (
+        "nomic-embed-text-v1.5-q",
+        EmbeddingModel::NomicEmbedTextV15Q,
+    ),
+    (
+        "paraphrase-multilingual-minilm-l12-v2",
+        EmbeddingModel::ParaphraseMLMiniLML12V2,
+    ),
+    (
+        "paraphrase-multilingual-minilm-l12-v2-q",
+        EmbeddingModel::ParaphraseMLMiniLML12V2Q,
+    ),
+    (
+        "paraphrase-multilingual-mpnet-base-v2",
+        EmbeddingModel::ParaphraseMLMpnetBaseV2,
+    ),
+    ("multilingual-e5-small", EmbeddingModel::MultilingualE5Small),
+    ("multilingual-e5-base", EmbeddingModel::MultilingualE5Base),
+    ("multilingual-e5-large", EmbeddingModel::MultilingualE5Large),
+    ("mxbai-embed-large-v1", EmbeddingModel::MxbaiEmbedLargeV1),
+    ("mxbai-embed-large-v1-q", EmbeddingModel::MxbaiEmbedLargeV1Q),
+];
+
+pub fn parse_model(name: &str) -> Result<EmbeddingModel> {
+    MODELS
+        .iter()
+        .find(|(model_name, _)| *model_name == name)
+        .map(|(_, model)| model.clone())
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown embedding model {}, expected one of {}",
+                name,
+                MODELS.map(|(model_name, _)| model_name).join(", ")
+            )
+        })
+}
+
+/// The length of the embeddings the model generates
+pub fn model_dim(name: &str) -> Result<usize> {
+    Ok(TextEmbedding::get_model_info(&parse_model(name)?).dim)
+}
+
+/// The files of a local model directory, as written by `fetch_model`
+pub const MODEL_FILE: &str = "model.onnx";
+pub const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+pub const MANIFEST_FILE: &str = "model.json";
+
+/// Records which model a local model directory holds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModelManifest {
+    pub model_name: String,
+    pub dim: usize,
+}
+
+/// Loads the configured model from `model_dir` when it is set, otherwise
+/// downloads it into fastembed's cache
+pub fn init(settings: &Settings) -> Result<TextEmbedding> {
+    if let Some(model_dir) = &settings.model_dir {
+        return init_from_dir(&settings.embedding_model, Path::new(model_dir));
+    }
 
-pub fn init() -> Result<TextEmbedding> {
     let model = TextEmbedding::try_new(InitOptions {
+        model_name: parse_model(&settings.embedding_model)?,
         show_download_progress: true,
         ..Default::default()
     })?;
@@ -10,8 +102,166 @@ pub fn init() -> Result<TextEmbedding> {
     Ok(model)
 }
 
+fn init_from_dir(name: &str, model_dir: &Path) -> Result<TextEmbedding> {
+    let read = |file: &str| -> Result<Vec<u8>> {
+        let path = model_dir.join(file);
+        std::fs::read(&path).with_context(|| {
+            format!(
+                "Missing model file {}. Run fetch_model on a machine with internet access and copy its output to {}",
+                path.display(),
+                model_dir.display()
+            )
+        })
+    };
+
+    let manifest: ModelManifest = serde_json::from_slice(&read(MANIFEST_FILE)?)
+        .with_context(|| format!("Invalid {} in {}", MANIFEST_FILE, model_dir.display()))?;
+    if manifest.model_name != name {
+        return Err(anyhow!(
+            "{} holds the model {}, but {} is configured",
+            model_dir.display(),
+            manifest.model_name,
+            name
+        ));
+    }
+
+    let model = TextEmbedding::try_new_from_user_defined(
+        UserDefinedEmbeddingModel {
+            onnx_file: read(MODEL_FILE)?,
+            tokenizer_files: TokenizerFiles {
+                tokenizer_file: read(TOKENIZER_FILES[0])?,
+                config_file: read(TOKENIZER_FILES[1])?,
+                special_tokens_map_file: read(TOKENIZER_FILES[2])?,
+                tokenizer_config_file: read(TOKENIZER_FILES[3])?,
+            },
+        },
+        InitOptionsUserDefined::default(),
+    )?;
+
+    Ok(model)
+}
+
+/// A model loaded once and shared by every request, instead of being loaded
+/// for each query
+pub struct Embedder {
+    pub model_name: String,
+    model: TextEmbedding,
+}
+
+/// A query embedded with the model named `model_name`
+#[derive(Debug, Clone)]
+pub struct QueryEmbedding {
+    pub model_name: String,
+    pub embedding: Embedding,
+}
+
+impl Embedder {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        Ok(Embedder {
+            model_name: settings.embedding_model.clone(),
+            model: init(settings)?,
+        })
+    }
+
+    /// Embeds the query on the blocking thread pool, so running the model
+    /// doesn't stall the async runtime
+    pub async fn embed_query(self: &Arc<Self>, query: &str) -> Result<QueryEmbedding> {
+        let embedder = self.clone();
+        let query = query.to_string();
+        tokio::task::spawn_blocking(move || {
+            Ok(QueryEmbedding {
+                model_name: embedder.model_name.clone(),
+                embedding: string_to_embedding(&query, &embedder.model)?,
+            })
+        })
+        .await?
+    }
+}
+
+/// The vec tables were built with a different model than the configured one,
+/// so query embeddings can't be compared with them
+#[derive(Debug)]
+pub struct ModelMismatch {
+    pub configured: String,
+    pub indexed: Option<String>,
+}
+
+impl fmt::Display for ModelMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Embedding model {} is configured, but the index was built with {}. Re-run scryfall_convert to rebuild it",
+            self.configured,
+            self.indexed.as_deref().unwrap_or("an unknown model")
+        )
+    }
+}
+
+impl std::error::Error for ModelMismatch {}
+
 pub fn string_to_embedding(inp: &str, model: &TextEmbedding) -> Result<Vec<f32>> {
     let res = model.embed(vec![inp], None)?;
 
     Ok(res[0].to_owned())
 }
+
+/// A unit of work for `embed_pipeline`, e.g. a page of cards
+pub trait EmbeddingBatch: Send {
+    /// The texts to embed, in the order their embeddings are handed back
+    fn texts(&self) -> Vec<&str>;
+}
+
+/// Embeds batches in three stages connected by bounded channels: `read` runs
+/// on its own thread, the batches are embedded on another, and `write` runs
+/// on the calling thread.
+///
+/// There is a single embedding stage because the model's session already
+/// spreads each batch across every core; more embedding threads would only
+/// contend for them. `write` gets the batches in the order `read` produced
+/// them, with one embedding per text, so the writer can checkpoint its progress.
+pub fn embed_pipeline<B, R, W>(model: &TextEmbedding, mut read: R, mut write: W) -> Result<()>
+where
+    B: EmbeddingBatch,
+    R: FnMut() -> Result<Option<B>> + Send,
+    W: FnMut(B, Vec<Embedding>) -> Result<()>,
+{
+    let (batch_tx, batch_rx) = sync_channel::<B>(2);
+    let (embedded_tx, embedded_rx) = sync_channel::<Result<(B, Vec<Embedding>)>>(2);
+
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || -> Result<()> {
+            while let Some(batch) = read()? {
+                // The embedder only hangs up when the writer failed
+                if batch_tx.send(batch).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        // Only the embedder holds on to the channels, so a failing stage unblocks the others
+        scope.spawn(move || {
+            for batch in batch_rx {
+                let texts = batch.texts();
+                let embedded = if texts.is_empty() {
+                    Ok(Vec::new())
+                } else {
+                    let batch_size = texts.len();
+                    model.embed(texts, Some(batch_size))
+                };
+                if embedded_tx.send(embedded.map(|e| (batch, e))).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let written = embedded_rx.into_iter().try_for_each(|embedded| {
+            let (batch, embeddings) = embedded?;
+            write(batch, embeddings)
+        });
+        let read = reader
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("Embedding reader panicked")));
+        written.and(read)
+    })
+}
@@ -1,3 +1,7 @@
+pub mod config;
 pub mod db;
+pub mod deck;
 pub mod embedings;
+pub mod query;
 pub mod routes;
+pub mod scryfall;
@@ -1,7 +1,20 @@
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 use mtg::{
-    db::{init_conn, DbConnection},
-    routes::{get_card_vec_info, get_cards, get_vector_version},
+    config,
+    db::{
+        init_conn,
+        vectors::{check_embedding_index, VecTable},
+        DbConnection,
+    },
+    embedings::Embedder,
+    routes::{
+        get_all_sets, get_card_price_history, get_card_vec_info, get_cards, get_cards_in_set,
+        get_dataset_info, get_related_cards, get_rulings, get_rulings_for_card, get_set_by_code,
+        get_vector_version, post_deck_tokens, AppState,
+    },
 };
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -9,18 +22,48 @@ use tower_http::services::ServeDir;
 
 #[tokio::main]
 async fn main() {
-    let conn = init_conn().expect("Failed to init db");
-    let db = Arc::new(DbConnection(Mutex::new(conn)));
+    let (settings, args) = config::load().expect("Failed to load settings");
+    if let Some(arg) = args.first() {
+        println!("Usage: main [settings]\n\n{}", config::USAGE);
+        let help = arg == "--help" || arg == "-h";
+        if !help {
+            eprintln!("Unknown argument {}", arg);
+        }
+        std::process::exit(if help { 0 } else { 1 });
+    }
+
+    let conn = init_conn(&settings).expect("Failed to init db");
+    // Semantic queries are refused until the index is rebuilt, the rest of the api still works
+    if let Err(e) = check_embedding_index(&conn, &settings.embedding_model, VecTable::Cards) {
+        println!("Semantic search is disabled: {}", e);
+    }
+    let state = AppState {
+        db: Arc::new(DbConnection(Mutex::new(conn))),
+        settings: Arc::new(settings.clone()),
+        // Loaded once here rather than for every semantic query
+        embedder: Arc::new(Embedder::new(&settings).expect("Failed to load embedding model")),
+    };
     // Create a new router
     let app = Router::new()
         .route("/api/cards", get(get_cards))
+        .route("/api/cards/:id/prices", get(get_card_price_history))
+        .route("/api/cards/:id/rulings", get(get_rulings_for_card))
+        .route("/api/cards/:id/related", get(get_related_cards))
+        .route("/api/decks/tokens", post(post_deck_tokens))
+        .route("/api/rulings", get(get_rulings))
+        .route("/api/sets", get(get_all_sets))
+        .route("/api/sets/:code", get(get_set_by_code))
+        .route("/api/sets/:code/cards", get(get_cards_in_set))
+        .route("/api/dataset", get(get_dataset_info))
         .route("/api/vec_version", get(get_vector_version))
         .route("/api/card_vec_info", get(get_card_vec_info))
-        .nest_service("/", ServeDir::new("www"))
-        .with_state(db);
+        .nest_service("/", ServeDir::new(&settings.static_dir))
+        .with_state(state);
 
     // Start the server
-    println!("Server starting on port 3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("Server starting on {}", settings.bind_addr);
+    let listener = tokio::net::TcpListener::bind(&settings.bind_addr)
+        .await
+        .unwrap();
     axum::serve(listener, app).await.unwrap();
 }
@@ -0,0 +1,872 @@
+//! A scryfall like search syntax, e.g.
+//! `t:creature c:g cmc<=3 o:"draw a card" (r:rare or r:mythic) -t:legendary`.
+//!
+//! Terms are and-ed together, `or` between terms ors them, `-` negates a term
+//! or a group and parentheses group terms. A term without a key matches the
+//! card name. See <https://scryfall.com/docs/syntax>
+//!
+//! `parse` turns the text into a `Query`, which `Query::to_sql` compiles into
+//! a condition on `c`, the cards table, with its values as named parameters.
+
+use rusqlite::types::Value;
+use serde::Serialize;
+use std::fmt;
+
+use crate::scryfall::parse_card_colors;
+
+/// A query that could not be parsed, pointing at the token that is wrong.
+/// `start` and `end` are byte offsets into the query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryError {
+    pub message: String,
+    pub token: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at `{}` ({}..{})",
+            self.message, self.token, self.start, self.end
+        )
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Filter(Filter),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `:`, which means "contains" for text and colors
+    Matches,
+    Eq,
+    NotEq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+impl Op {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Op::Matches => ":",
+            Op::Eq => "=",
+            Op::NotEq => "!=",
+            Op::Lt => "<",
+            Op::Lte => "<=",
+            Op::Gt => ">",
+            Op::Gte => ">=",
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Op::Matches | Op::Eq => "=",
+            Op::NotEq => "!=",
+            Op::Lt => "<",
+            Op::Lte => "<=",
+            Op::Gt => ">",
+            Op::Gte => ">=",
+        }
+    }
+}
+
+/// Card text fields, matched as case insensitive substrings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextField {
+    Name,
+    Type,
+    Oracle,
+    Flavor,
+    Artist,
+}
+
+/// Fields compared as numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberField {
+    ManaValue,
+    Power,
+    Toughness,
+    Price,
+    Year,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorField {
+    Colors,
+    Identity,
+}
+
+/// Exact matches on a set's fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetField {
+    Code,
+    SetType,
+    Block,
+}
+
+/// The legality statuses `f:`, `banned:` and `restricted:` look for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegalityField {
+    Legal,
+    Banned,
+    Restricted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Digital,
+    Multicolor,
+    Colorless,
+    Multifaced,
+    Image,
+    Flavor,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Text(TextField, Op, String),
+    Number(NumberField, Op, f64),
+    Color(ColorField, Op, i64),
+    /// Rank in `RARITIES`
+    Rarity(Op, i64),
+    Set(SetField, Op, String),
+    Keyword(String),
+    Legality(LegalityField, String),
+    /// Release date as `YYYY-MM-DD`, or a prefix of it
+    Date(Op, String),
+    Is(Flag),
+}
+
+/// Rarities from lowest to highest, so they can be compared by rank
+const RARITIES: [&str; 6] = ["common", "uncommon", "rare", "special", "mythic", "bonus"];
+
+/// How deep groups and negations can nest. Parsing, compiling and dropping a
+/// query all recurse, so without a limit a query of a few thousand `(` would
+/// overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+pub fn parse(query: &str) -> Result<Query, QueryError> {
+    let tokens = lex(query)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        depth: 0,
+        query,
+    };
+    let parsed = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        // Only an unmatched `)` stops parse_or before the end
+        return Err(token.error("Unmatched closing parenthesis"));
+    }
+    Ok(parsed)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Or,
+    Not,
+    Word(String),
+    Term { key: String, op: Op, value: String },
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+impl Token {
+    fn error(&self, message: &str) -> QueryError {
+        QueryError {
+            message: message.to_string(),
+            token: self.text.clone(),
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+fn lex(query: &str) -> Result<Vec<Token>, QueryError> {
+    let chars: Vec<(usize, char)> = query.char_indices().collect();
+    let offset = |i: usize| chars.get(i).map_or(query.len(), |(o, _)| *o);
+    let is_delimiter = |c: char| c.is_whitespace() || c == '(' || c == ')';
+
+    // Reads a quoted string starting at the quote at `i`, returning the
+    // string and the index after the closing quote
+    let read_quoted = |i: usize| -> Result<(String, usize), QueryError> {
+        let mut end = i + 1;
+        while end < chars.len() && chars[end].1 != '"' {
+            end += 1;
+        }
+        if end == chars.len() {
+            return Err(QueryError {
+                message: "Unterminated quote".to_string(),
+                token: query[offset(i)..].to_string(),
+                start: offset(i),
+                end: query.len(),
+            });
+        }
+        Ok((query[offset(i + 1)..offset(end)].to_string(), end + 1))
+    };
+    let read_bare = |i: usize| -> usize {
+        let mut end = i;
+        while end < chars.len() && !is_delimiter(chars[end].1) {
+            end += 1;
+        }
+        end
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i].1;
+        let start = i;
+        let kind = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c == '(' {
+            i += 1;
+            TokenKind::Open
+        } else if c == ')' {
+            i += 1;
+            TokenKind::Close
+        } else if c == '-' && chars.get(i + 1).is_some_and(|(_, n)| !n.is_whitespace()) {
+            i += 1;
+            TokenKind::Not
+        } else if c == '"' {
+            let (word, end) = read_quoted(i)?;
+            i = end;
+            TokenKind::Word(word)
+        } else {
+            let mut key_end = i;
+            while key_end < chars.len() && chars[key_end].1.is_ascii_alphabetic() {
+                key_end += 1;
+            }
+            let rest = &query[offset(key_end)..];
+            let op = [
+                ("!=", Op::NotEq),
+                ("<=", Op::Lte),
+                (">=", Op::Gte),
+                (":", Op::Matches),
+                ("=", Op::Eq),
+                ("<", Op::Lt),
+                (">", Op::Gt),
+            ]
+            .into_iter()
+            .find(|(symbol, _)| rest.starts_with(symbol));
+
+            match op {
+                Some((symbol, op)) if key_end > i => {
+                    let key = query[offset(i)..offset(key_end)].to_lowercase();
+                    let value_start = key_end + symbol.len();
+                    let (value, end) = if chars.get(value_start).is_some_and(|(_, c)| *c == '"') {
+                        read_quoted(value_start)?
+                    } else {
+                        let end = read_bare(value_start);
+                        (query[offset(value_start)..offset(end)].to_string(), end)
+                    };
+                    i = end;
+                    TokenKind::Term { key, op, value }
+                }
+                _ => {
+                    i = read_bare(i);
+                    let word = &query[offset(start)..offset(i)];
+                    if word.eq_ignore_ascii_case("or") {
+                        TokenKind::Or
+                    } else if word.eq_ignore_ascii_case("and") {
+                        // Terms are and-ed anyway
+                        continue;
+                    } else {
+                        TokenKind::Word(word.to_string())
+                    }
+                }
+            }
+        };
+        tokens.push(Token {
+            kind,
+            start: offset(start),
+            end: offset(i),
+            text: query[offset(start)..offset(i)].to_string(),
+        });
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    /// Groups and negations around the term being parsed
+    depth: usize,
+    query: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn end_error(&self, message: &str) -> QueryError {
+        QueryError {
+            message: message.to_string(),
+            token: String::new(),
+            start: self.query.len(),
+            end: self.query.len(),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut terms = vec![self.parse_and()?];
+        while let Some(token) = self.peek() {
+            if token.kind != TokenKind::Or {
+                break;
+            }
+            self.next();
+            terms.push(self.parse_and()?);
+        }
+        Ok(match terms.len() {
+            1 => terms.remove(0),
+            _ => Query::Or(terms),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut terms = Vec::new();
+        while let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::Or | TokenKind::Close => break,
+                _ => terms.push(self.parse_unary()?),
+            }
+        }
+        match (terms.len(), self.peek()) {
+            (0, Some(token)) => Err(token.error("Expected a search term")),
+            (0, None) => Err(self.end_error("Expected a search term")),
+            (1, _) => Ok(terms.remove(0)),
+            _ => Ok(Query::And(terms)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => return Err(self.end_error("Expected a search term")),
+        };
+        match &token.kind {
+            TokenKind::Not | TokenKind::Open if self.depth == MAX_DEPTH => {
+                Err(token.error(&format!(
+                    "Can't nest groups or negations more than {} deep",
+                    MAX_DEPTH
+                )))
+            }
+            TokenKind::Not => {
+                self.depth += 1;
+                let query = self.parse_unary()?;
+                self.depth -= 1;
+                Ok(Query::Not(Box::new(query)))
+            }
+            TokenKind::Open => {
+                self.depth += 1;
+                let query = self.parse_or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Close,
+                        ..
+                    }) => Ok(query),
+                    _ => Err(token.error("Unmatched opening parenthesis")),
+                }
+            }
+            TokenKind::Word(word) => Ok(Query::Filter(Filter::Text(
+                TextField::Name,
+                Op::Matches,
+                word.clone(),
+            ))),
+            TokenKind::Term { key, op, value } => parse_filter(key, *op, value)
+                .map(Query::Filter)
+                .map_err(|message| token.error(&message)),
+            TokenKind::Or | TokenKind::Close => Err(token.error("Expected a search term")),
+        }
+    }
+}
+
+/// Errors are messages, the caller points them at the token
+fn parse_filter(key: &str, op: Op, value: &str) -> Result<Filter, String> {
+    if value.is_empty() {
+        return Err(format!("Missing a value after {}{}", key, op.as_str()));
+    }
+    let text_op = || match op {
+        Op::Matches | Op::Eq | Op::NotEq => Ok(op),
+        _ => Err(format!("{} can't be compared with {}", key, op.as_str())),
+    };
+    let only_matches = || match op {
+        Op::Matches | Op::Eq => Ok(()),
+        _ => Err(format!("{} only supports : and =", key)),
+    };
+    let number = || {
+        value
+            .parse::<f64>()
+            .map_err(|_| format!("{} is not a number", value))
+    };
+
+    let filter = match key {
+        "n" | "name" => Filter::Text(TextField::Name, text_op()?, value.to_string()),
+        "t" | "type" => Filter::Text(TextField::Type, text_op()?, value.to_string()),
+        "o" | "oracle" => Filter::Text(TextField::Oracle, text_op()?, value.to_string()),
+        "ft" | "flavor" => Filter::Text(TextField::Flavor, text_op()?, value.to_string()),
+        "a" | "artist" => Filter::Text(TextField::Artist, text_op()?, value.to_string()),
+        "cmc" | "mv" | "manavalue" => Filter::Number(NumberField::ManaValue, op, number()?),
+        "pow" | "power" => Filter::Number(NumberField::Power, op, number()?),
+        "tou" | "toughness" => Filter::Number(NumberField::Toughness, op, number()?),
+        "usd" => Filter::Number(NumberField::Price, op, number()?),
+        "year" => Filter::Number(NumberField::Year, op, number()?),
+        "c" | "color" | "colors" => Filter::Color(ColorField::Colors, op, parse_colors(value)?),
+        "id" | "identity" => Filter::Color(ColorField::Identity, op, parse_colors(value)?),
+        "r" | "rarity" => Filter::Rarity(op, parse_rarity(value)?),
+        "s" | "e" | "set" | "edition" => Filter::Set(SetField::Code, text_op()?, value.to_string()),
+        "st" | "settype" => Filter::Set(SetField::SetType, text_op()?, value.to_string()),
+        "b" | "block" => Filter::Set(SetField::Block, text_op()?, value.to_string()),
+        "kw" | "keyword" => {
+            only_matches()?;
+            Filter::Keyword(value.to_string())
+        }
+        "f" | "format" | "legal" => {
+            only_matches()?;
+            Filter::Legality(LegalityField::Legal, value.to_lowercase())
+        }
+        "banned" => {
+            only_matches()?;
+            Filter::Legality(LegalityField::Banned, value.to_lowercase())
+        }
+        "restricted" => {
+            only_matches()?;
+            Filter::Legality(LegalityField::Restricted, value.to_lowercase())
+        }
+        "date" => {
+            if !value.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                return Err(format!("{} is not a YYYY-MM-DD date", value));
+            }
+            Filter::Date(op, value.to_string())
+        }
+        "is" | "has" => {
+            only_matches()?;
+            Filter::Is(match (key, value.to_lowercase().as_str()) {
+                ("is", "digital") => Flag::Digital,
+                ("is", "multicolor" | "multicolored") => Flag::Multicolor,
+                ("is", "colorless") => Flag::Colorless,
+                ("is", "multifaced" | "dfc" | "mdfc") => Flag::Multifaced,
+                ("has", "image") => Flag::Image,
+                ("has", "flavor") => Flag::Flavor,
+                _ => return Err(format!("Unknown {}:{}", key, value)),
+            })
+        }
+        _ => return Err(format!("Unknown keyword {}", key)),
+    };
+    Ok(filter)
+}
+
+fn parse_colors(value: &str) -> Result<i64, String> {
+    parse_card_colors(value).map_err(|e| e.to_string())
+}
+
+fn parse_rarity(value: &str) -> Result<i64, String> {
+    let value = value.to_lowercase();
+    RARITIES
+        .iter()
+        .position(|r| *r == value || (value.len() == 1 && r.starts_with(&value)))
+        .map(|rank| rank as i64)
+        .ok_or(format!(
+            "Unknown rarity {}, expected one of {}",
+            value,
+            RARITIES.join(", ")
+        ))
+}
+
+/// Compiles a query into a condition on `c`, the cards table
+pub struct SqlQuery {
+    pub sql: String,
+    /// Named parameters of `sql`, `:q0`, `:q1`...
+    pub params: Vec<(String, Value)>,
+}
+
+impl Query {
+    pub fn to_sql(&self) -> SqlQuery {
+        let mut params = Vec::new();
+        let sql = self.compile(&mut params);
+        SqlQuery { sql, params }
+    }
+
+    fn compile(&self, params: &mut Vec<(String, Value)>) -> String {
+        match self {
+            Query::And(terms) => join(terms, " AND ", params),
+            Query::Or(terms) => join(terms, " OR ", params),
+            // A NULL column doesn't match, so its negation should
+            Query::Not(query) => format!("NOT COALESCE({}, 0)", query.compile(params)),
+            Query::Filter(filter) => filter.compile(params),
+        }
+    }
+}
+
+fn join(terms: &[Query], separator: &str, params: &mut Vec<(String, Value)>) -> String {
+    let terms: Vec<String> = terms.iter().map(|t| t.compile(params)).collect();
+    format!("({})", terms.join(separator))
+}
+
+fn param(params: &mut Vec<(String, Value)>, value: Value) -> String {
+    let name = format!(":q{}", params.len());
+    params.push((name.clone(), value));
+    name
+}
+
+impl Filter {
+    fn compile(&self, params: &mut Vec<(String, Value)>) -> String {
+        match self {
+            Filter::Text(field, op, text) => {
+                let column = match field {
+                    TextField::Name => "name",
+                    TextField::Type => "type_line",
+                    TextField::Oracle => "oracle_text",
+                    TextField::Flavor => "flavor_text",
+                    TextField::Artist => "artist",
+                };
+                let p = param(params, Value::Text(format!("%{}%", text)));
+                // Multi-faced cards may only have the text on their faces
+                let matches = format!(
+                    "(c.{column} LIKE {p} COLLATE NOCASE OR EXISTS (
+                        SELECT 1 FROM card_faces as f WHERE f.card_id = c.id AND f.{column} LIKE {p} COLLATE NOCASE
+                    ))"
+                );
+                match op {
+                    Op::NotEq => format!("NOT COALESCE({}, 0)", matches),
+                    _ => matches,
+                }
+            }
+            Filter::Number(field, op, number) => {
+                let p = param(params, Value::Real(*number));
+                let column = match field {
+                    NumberField::ManaValue => "c.cmc".to_string(),
+                    // Power and toughness can be `*`, which isn't comparable
+                    NumberField::Power => {
+                        return format!(
+                            "(c.power GLOB '[0-9]*' AND CAST(c.power AS REAL) {} {p})",
+                            op.sql()
+                        )
+                    }
+                    NumberField::Toughness => {
+                        return format!(
+                            "(c.toughness GLOB '[0-9]*' AND CAST(c.toughness AS REAL) {} {p})",
+                            op.sql()
+                        )
+                    }
+                    NumberField::Price => "(SELECT pr.usd FROM card_prices as pr WHERE pr.card_id = c.id ORDER BY pr.snapshot_date DESC LIMIT 1)".to_string(),
+                    NumberField::Year => "CAST(substr(c.released_at, 1, 4) AS INTEGER)".to_string(),
+                };
+                format!("{} {} {}", column, op.sql(), p)
+            }
+            Filter::Color(field, op, mask) => {
+                let column = match field {
+                    ColorField::Colors => "c.colors",
+                    ColorField::Identity => "c.color_identity",
+                };
+                let p = param(params, Value::Integer(*mask));
+                match op {
+                    // Like scryfall, `:` means at least these colors, except
+                    // for colorless, which has no colors to include
+                    Op::Matches if *mask == 0 => format!("{column} = {p}"),
+                    Op::Matches | Op::Gte => format!("({column} & {p}) = {p}"),
+                    Op::Gt => format!("(({column} & {p}) = {p} AND {column} != {p})"),
+                    Op::Lte => format!("({column} | {p}) = {p}"),
+                    Op::Lt => format!("(({column} | {p}) = {p} AND {column} != {p})"),
+                    Op::Eq => format!("{column} = {p}"),
+                    Op::NotEq => format!("{column} != {p}"),
+                }
+            }
+            Filter::Rarity(op, rank) => {
+                let ranks: Vec<String> = RARITIES
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, rarity)| format!("WHEN '{}' THEN {}", rarity, rank))
+                    .collect();
+                let p = param(params, Value::Integer(*rank));
+                format!("(CASE c.rarity {} END) {} {}", ranks.join(" "), op.sql(), p)
+            }
+            Filter::Set(field, op, value) => {
+                let column = match field {
+                    SetField::Code => "c.set_code",
+                    SetField::SetType => "(SELECT st.set_type FROM sets as st WHERE st.code = c.set_code)",
+                    SetField::Block => "(SELECT st.block FROM sets as st WHERE st.code = c.set_code)",
+                };
+                let p = param(params, Value::Text(value.clone()));
+                format!("{} {} {} COLLATE NOCASE", column, op.sql(), p)
+            }
+            Filter::Keyword(keyword) => {
+                let p = param(params, Value::Text(keyword.clone()));
+                format!("EXISTS (SELECT 1 FROM card_keywords as kw WHERE kw.card_id = c.id AND kw.keyword = {p})")
+            }
+            Filter::Legality(field, format) => {
+                let statuses = match field {
+                    LegalityField::Legal => "'legal', 'restricted'",
+                    LegalityField::Banned => "'banned'",
+                    LegalityField::Restricted => "'restricted'",
+                };
+                let p = param(params, Value::Text(format.clone()));
+                format!("EXISTS (SELECT 1 FROM card_legalities as lg WHERE lg.card_id = c.id AND lg.format = {p} AND lg.status IN ({statuses}))")
+            }
+            Filter::Date(op, date) => {
+                let p = param(params, Value::Text(date.clone()));
+                match op {
+                    // `date:2020` or `date:2020-06` match the whole year or month
+                    Op::Matches => format!("c.released_at LIKE {} || '%'", p),
+                    _ => format!("c.released_at {} {}", op.sql(), p),
+                }
+            }
+            Filter::Is(flag) => match flag {
+                Flag::Digital => "c.digital = 1".to_string(),
+                Flag::Multicolor => "(c.colors & (c.colors - 1)) != 0".to_string(),
+                Flag::Colorless => "c.colors = 0".to_string(),
+                Flag::Multifaced => {
+                    "EXISTS (SELECT 1 FROM card_faces as f WHERE f.card_id = c.id)".to_string()
+                }
+                Flag::Image => "(EXISTS (SELECT 1 FROM image_uris as i WHERE i.card_id = c.id) OR EXISTS (SELECT 1 FROM card_faces as f WHERE f.card_id = c.id AND f.normal IS NOT NULL))".to_string(),
+                Flag::Flavor => "(c.flavor_text IS NOT NULL OR EXISTS (SELECT 1 FROM card_faces as f WHERE f.card_id = c.id AND f.flavor_text IS NOT NULL))".to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(word: &str) -> Query {
+        Query::Filter(Filter::Text(TextField::Name, Op::Matches, word.to_string()))
+    }
+
+    fn kinds(query: &str) -> Vec<TokenKind> {
+        lex(query).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn lexes_terms_words_and_groups() {
+        let tokens = lex(r#"t:creature "draw a card" -(cmc>=3 OR x)"#).unwrap();
+        let spans: Vec<(usize, usize, &str)> = tokens
+            .iter()
+            .map(|t| (t.start, t.end, t.text.as_str()))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 10, "t:creature"),
+                (11, 24, "\"draw a card\""),
+                (25, 26, "-"),
+                (26, 27, "("),
+                (27, 33, "cmc>=3"),
+                (34, 36, "OR"),
+                (37, 38, "x"),
+                (38, 39, ")"),
+            ]
+        );
+        assert_eq!(
+            tokens.into_iter().map(|t| t.kind).collect::<Vec<_>>(),
+            vec![
+                TokenKind::Term {
+                    key: "t".to_string(),
+                    op: Op::Matches,
+                    value: "creature".to_string()
+                },
+                TokenKind::Word("draw a card".to_string()),
+                TokenKind::Not,
+                TokenKind::Open,
+                TokenKind::Term {
+                    key: "cmc".to_string(),
+                    op: Op::Gte,
+                    value: "3".to_string()
+                },
+                TokenKind::Or,
+                TokenKind::Word("x".to_string()),
+                TokenKind::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexes_quoted_values_and_skips_and() {
+        assert_eq!(
+            kinds(r#"O:"draw a card" and - x"#),
+            vec![
+                TokenKind::Term {
+                    key: "o".to_string(),
+                    op: Op::Matches,
+                    value: "draw a card".to_string()
+                },
+                // A lone `-` isn't a negation
+                TokenKind::Word("-".to_string()),
+                TokenKind::Word("x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexes_comparison_operators() {
+        let ops: Vec<Op> = lex("a:1 a=1 a!=1 a<1 a<=1 a>1 a>=1")
+            .unwrap()
+            .into_iter()
+            .map(|t| match t.kind {
+                TokenKind::Term { op, .. } => op,
+                kind => panic!("Expected a term, got {:?}", kind),
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Matches,
+                Op::Eq,
+                Op::NotEq,
+                Op::Lt,
+                Op::Lte,
+                Op::Gt,
+                Op::Gte
+            ]
+        );
+    }
+
+    #[test]
+    fn implicit_and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a b or c").unwrap(),
+            Query::Or(vec![Query::And(vec![name("a"), name("b")]), name("c")])
+        );
+        assert_eq!(
+            parse("a (b or c)").unwrap(),
+            Query::And(vec![name("a"), Query::Or(vec![name("b"), name("c")])])
+        );
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(
+            parse("-t:legendary -(a or b)").unwrap(),
+            Query::And(vec![
+                Query::Not(Box::new(Query::Filter(Filter::Text(
+                    TextField::Type,
+                    Op::Matches,
+                    "legendary".to_string()
+                )))),
+                Query::Not(Box::new(Query::Or(vec![name("a"), name("b")]))),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            parse(r#"o:"draw a card" cmc<=3 pow>2 c>=wu r:m usd!=1.5"#).unwrap(),
+            Query::And(vec![
+                Query::Filter(Filter::Text(
+                    TextField::Oracle,
+                    Op::Matches,
+                    "draw a card".to_string()
+                )),
+                Query::Filter(Filter::Number(NumberField::ManaValue, Op::Lte, 3.0)),
+                Query::Filter(Filter::Number(NumberField::Power, Op::Gt, 2.0)),
+                Query::Filter(Filter::Color(ColorField::Colors, Op::Gte, 0b11)),
+                Query::Filter(Filter::Rarity(Op::Matches, 4)),
+                Query::Filter(Filter::Number(NumberField::Price, Op::NotEq, 1.5)),
+            ])
+        );
+        assert_eq!(
+            parse("c:c id=colorless").unwrap(),
+            Query::And(vec![
+                Query::Filter(Filter::Color(ColorField::Colors, Op::Matches, 0)),
+                Query::Filter(Filter::Color(ColorField::Identity, Op::Eq, 0)),
+            ])
+        );
+    }
+
+    #[test]
+    fn compiles_to_parameterized_sql() {
+        let compiled = parse("cmc<=3 (c:g or -is:digital)").unwrap().to_sql();
+        assert_eq!(
+            compiled.sql,
+            "(c.cmc <= :q0 AND ((c.colors & :q1) = :q1 OR NOT COALESCE(c.digital = 1, 0)))"
+        );
+        assert_eq!(
+            compiled.params,
+            vec![
+                (":q0".to_string(), Value::Real(3.0)),
+                (":q1".to_string(), Value::Integer(16)),
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_text_as_a_like_parameter() {
+        let compiled = parse("t!=\"elf's\"").unwrap().to_sql();
+        assert!(compiled
+            .sql
+            .starts_with("NOT COALESCE((c.type_line LIKE :q0"));
+        assert!(!compiled.sql.contains("elf"));
+        assert_eq!(
+            compiled.params,
+            vec![(":q0".to_string(), Value::Text("%elf's%".to_string()))]
+        );
+    }
+
+    fn error_at(query: &str) -> (String, usize, usize) {
+        let error = parse(query).unwrap_err();
+        (error.token, error.start, error.end)
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_token() {
+        assert_eq!(error_at("t:elf foo:bar"), ("foo:bar".to_string(), 6, 13));
+        assert_eq!(error_at("a cmc>x"), ("cmc>x".to_string(), 2, 7));
+        assert_eq!(error_at("a t<elf"), ("t<elf".to_string(), 2, 7));
+        assert_eq!(error_at("a o:"), ("o:".to_string(), 2, 4));
+        assert_eq!(error_at(r#"a o:"draw"#), ("\"draw".to_string(), 4, 9));
+        assert_eq!(error_at("a c:wc"), ("c:wc".to_string(), 2, 6));
+        assert_eq!(error_at("a b)"), (")".to_string(), 3, 4));
+        assert_eq!(error_at("(a b"), ("(".to_string(), 0, 1));
+        assert_eq!(error_at("a or or b"), ("or".to_string(), 5, 7));
+        assert_eq!(error_at("a or"), (String::new(), 4, 4));
+        assert_eq!(error_at(""), (String::new(), 0, 0));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested(MAX_DEPTH)).unwrap(), name("a"));
+        assert_eq!(
+            error_at(&nested(10_000)),
+            ("(".to_string(), MAX_DEPTH, MAX_DEPTH + 1)
+        );
+        assert_eq!(
+            error_at(&format!("{}a", "-".repeat(10_000))),
+            ("-".to_string(), MAX_DEPTH, MAX_DEPTH + 1)
+        );
+        // Negations and groups count towards the same depth
+        assert_eq!(
+            error_at(&format!("{}a", "-(".repeat(10_000))),
+            ("-".to_string(), MAX_DEPTH, MAX_DEPTH + 1)
+        );
+    }
+}
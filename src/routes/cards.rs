@@ -1,21 +1,122 @@
-use crate::db::{search_cards, CardSearchType, DbConnection};
+use crate::{
+    db::{
+        get_card_prices, get_card_relations, get_deck_tokens, search_cards,
+        vectors::IncompleteEmbeddingIndex, Card, CardCursor, CardFilters, CardPage, CardSearchType,
+        CardSort, DbConnection, InvalidCursor, SortOrder,
+    },
+    deck::parse_decklist,
+    embedings::{Embedder, ModelMismatch},
+    query::{self, QueryError},
+    scryfall::{parse_card_colors, parse_color_mask},
+};
 use axum::{
-    extract::{Query, State},
-    response::IntoResponse,
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::{header, HeaderValue, Uri},
+    response::{IntoResponse, Response},
     Json,
 };
 use reqwest::StatusCode;
-use serde::Deserialize;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, sync::Arc};
 
 #[derive(Deserialize)]
 pub struct CardQueryParams {
     #[serde(default = "default_page")]
     page: u32,
+    /// Cards per page, at most `MAX_LIMIT`
     #[serde(default = "default_limit")]
     limit: u32,
+    /// The `next_cursor` or `prev_cursor` of another page, used instead of `page`
+    cursor: Option<String>,
     #[serde(default = "default_search")]
     search: String,
+    /// like, fts, semantic or hybrid
+    #[serde(default)]
+    mode: CardSearchType,
+    format: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    /// Exactly these colors, e.g. `wu`, or `c` for colorless
+    colors: Option<String>,
+    /// Color identity within these colors, e.g. `wubg`, or `c` for colorless
+    identity: Option<String>,
+    /// Produces at least these colors of mana, e.g. `g`
+    produces: Option<String>,
+    keyword: Option<String>,
+    /// Set code, e.g. `znr`
+    set: Option<String>,
+    /// common, uncommon, rare, mythic...
+    rarity: Option<String>,
+    cmc_min: Option<f64>,
+    cmc_max: Option<f64>,
+    /// Substring of the type line, e.g. `creature`
+    #[serde(rename = "type")]
+    type_line: Option<String>,
+    artist: Option<String>,
+    /// name, date, collector, cmc or price. Searches are ordered by relevance
+    /// without it, and with it semantic and hybrid ones only sort their best
+    /// matches.
+    sort: Option<CardSort>,
+    /// asc or desc
+    #[serde(default)]
+    order: SortOrder,
+    /// Scryfall style query, e.g. `t:creature c:g cmc<=3`
+    q: Option<String>,
+}
+
+/// A page of `/api/cards`
+#[derive(Serialize)]
+pub struct CardsPage {
+    items: Vec<Card>,
+    /// Number of cards the whole search matches
+    total: u64,
+    /// Hybrid searches can only estimate their total
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    total_estimated: bool,
+    /// Sorted semantic and hybrid searches match more cards than the `total`
+    /// best ones they sort and page through
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    total_capped: bool,
+    next_cursor: Option<String>,
+    prev_cursor: Option<String>,
+}
+
+/// The body of every `/api/cards` response that isn't a page
+#[derive(Serialize)]
+pub struct CardsError {
+    error: String,
+    /// Where the `q` query is wrong, when it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<QueryError>,
+}
+
+pub fn error_response(status: StatusCode, error: impl Display) -> Response {
+    let body = CardsError {
+        error: error.to_string(),
+        query: None,
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Query strings that don't deserialize, e.g. an unknown `sort`, get the same
+/// error body as every other failure instead of axum's plain text rejection
+fn rejection_response(rejection: QueryRejection) -> Response {
+    error_response(StatusCode::BAD_REQUEST, rejection.body_text())
+}
+
+/// The request's url with `cursor` in place of its page, for a `Link` header
+fn page_link(uri: &Uri, cursor: &str, rel: &str) -> String {
+    let mut query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            !param.is_empty() && !param.starts_with("cursor=") && !param.starts_with("page=")
+        })
+        .collect();
+    let cursor = format!("cursor={}", cursor);
+    query.push(&cursor);
+    format!("<{}?{}>; rel=\"{}\"", uri.path(), query.join("&"), rel)
 }
 
 pub fn default_page() -> u32 {
@@ -26,26 +127,249 @@ pub fn default_limit() -> u32 {
     25
 }
 
+/// The most cards a page can have
+pub const MAX_LIMIT: u32 = 250;
+
 pub fn default_search() -> String {
     String::new()
 }
 
 pub async fn get_cards(
     State(db): State<Arc<DbConnection>>,
-    params: Query<CardQueryParams>,
-) -> impl IntoResponse {
-    let page = params.page;
-    let limit = params.limit;
+    State(embedder): State<Arc<Embedder>>,
+    uri: Uri,
+    params: Result<Query<CardQueryParams>, QueryRejection>,
+) -> Response {
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(rejection) => return rejection_response(rejection),
+    };
+    if !(1..=MAX_LIMIT).contains(&params.limit) {
+        let message = format!("limit must be between 1 and {}", MAX_LIMIT);
+        return error_response(StatusCode::BAD_REQUEST, message);
+    }
+    let cursor = match params.cursor.as_deref().map(CardCursor::decode) {
+        Some(Ok(cursor)) => cursor,
+        Some(Err(e)) => return error_response(StatusCode::BAD_REQUEST, e),
+        None => match params.page.saturating_sub(1).checked_mul(params.limit) {
+            Some(offset) => CardCursor::Offset(offset),
+            None => {
+                let message = format!("page {} is out of range", params.page);
+                return error_response(StatusCode::BAD_REQUEST, message);
+            }
+        },
+    };
+    let page = CardPage {
+        cursor,
+        page_size: params.limit,
+        sort: params.sort,
+        order: params.order,
+    };
     let search = params.search.clone();
+    let color_masks = (
+        params.colors.as_deref().map(parse_card_colors).transpose(),
+        params
+            .identity
+            .as_deref()
+            .map(parse_card_colors)
+            .transpose(),
+        params.produces.as_deref().map(parse_color_mask).transpose(),
+    );
+    let (colors, identity, produces) = match color_masks {
+        (Ok(colors), Ok(identity), Ok(produces)) => (colors, identity, produces),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+    };
+    let query = match params.q.as_deref().filter(|q| !q.trim().is_empty()) {
+        Some(q) => match query::parse(q) {
+            Ok(query) => Some(query),
+            Err(e) => {
+                let body = CardsError {
+                    error: e.to_string(),
+                    query: Some(e),
+                };
+                return (StatusCode::BAD_REQUEST, Json(body)).into_response();
+            }
+        },
+        None => None,
+    };
+    let filters = CardFilters {
+        format: params.format.clone(),
+        min_price: params.min_price,
+        max_price: params.max_price,
+        colors,
+        identity,
+        produces,
+        keyword: params.keyword.clone(),
+        set: params.set.clone(),
+        rarity: params.rarity.clone(),
+        cmc_min: params.cmc_min,
+        cmc_max: params.cmc_max,
+        type_line: params.type_line.clone(),
+        artist: params.artist.clone(),
+        query,
+    };
+
+    // Embedded before taking the lock so other requests can use the db meanwhile
+    let query_embedding = match params.mode {
+        CardSearchType::Semantic | CardSearchType::Hybrid if !search.is_empty() => {
+            match embedder.embed_query(&search).await {
+                Ok(query_embedding) => Some(query_embedding),
+                Err(e) => {
+                    println!("Error embedding card search: {:?}", e);
+                    return error_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to embed the search",
+                    );
+                }
+            }
+        }
+        _ => None,
+    };
 
     let conn = db.0.lock().await;
 
-    match search_cards(&conn, &search, page, limit, CardSearchType::Like) {
-        Ok(cards) => (StatusCode::OK, Json(cards)),
+    match search_cards(
+        &conn,
+        &search,
+        &page,
+        params.mode,
+        &filters,
+        query_embedding.as_ref(),
+    ) {
+        Ok(results) => {
+            let next_cursor = results.next.map(|cursor| cursor.encode());
+            let prev_cursor = results.prev.map(|cursor| cursor.encode());
+            // RFC 8288 links, so clients can follow the pages without knowing the api
+            let links: Vec<String> = [(&next_cursor, "next"), (&prev_cursor, "prev")]
+                .into_iter()
+                .filter_map(|(cursor, rel)| Some(page_link(&uri, cursor.as_ref()?, rel)))
+                .collect();
+
+            let mut response = (
+                StatusCode::OK,
+                Json(CardsPage {
+                    items: results.cards,
+                    total: results.total,
+                    total_estimated: results.estimated,
+                    total_capped: results.capped,
+                    next_cursor,
+                    prev_cursor,
+                }),
+            )
+                .into_response();
+            if let Ok(links) = HeaderValue::try_from(links.join(", ")) {
+                if !links.is_empty() {
+                    response.headers_mut().insert(header::LINK, links);
+                }
+            }
+            response
+        }
+        Err(e) if e.is::<InvalidCursor>() => error_response(StatusCode::BAD_REQUEST, e),
+        Err(e) if e.is::<ModelMismatch>() || e.is::<IncompleteEmbeddingIndex>() => {
+            error_response(StatusCode::CONFLICT, e)
+        }
         Err(e) => {
             println!("Error finding cards: {:?}", e);
             // TODO: logger the error
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to find cards")
+        }
+    }
+}
+
+pub async fn get_card_price_history(
+    State(db): State<Arc<DbConnection>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_card_prices(&conn, &id) {
+        Ok(Some(prices)) => (StatusCode::OK, Json(prices)),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(vec![])),
+        Err(e) => {
+            println!("Error finding card prices: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+        }
+    }
+}
+
+pub async fn get_related_cards(
+    State(db): State<Arc<DbConnection>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_card_relations(&conn, &id) {
+        Ok(Some(relations)) => (StatusCode::OK, Json(relations)),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(vec![])),
+        Err(e) => {
+            println!("Error finding related cards: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+        }
+    }
+}
+
+/// Lists every token the cards of a plain text decklist can create
+pub async fn post_deck_tokens(
+    State(db): State<Arc<DbConnection>>,
+    decklist: String,
+) -> impl IntoResponse {
+    let card_names: Vec<String> = parse_decklist(&decklist)
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+
+    let conn = db.0.lock().await;
+
+    match get_deck_tokens(&conn, &card_names) {
+        Ok(tokens) => (StatusCode::OK, Json(tokens)),
+        Err(e) => {
+            println!("Error finding deck tokens: {:?}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    async fn params_error(uri: &str) -> (StatusCode, serde_json::Value) {
+        let uri: Uri = uri.parse().unwrap();
+        let rejection = Query::<CardQueryParams>::try_from_uri(&uri)
+            .err()
+            .expect("Expected the params to be refused");
+        let response = rejection_response(rejection);
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unknown_sort_is_a_json_error() {
+        let (status, body) = params_error("/api/cards?sort=bogus").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("bogus"));
+    }
+
+    #[tokio::test]
+    async fn non_numeric_limit_is_a_json_error() {
+        let (status, body) = params_error("/api/cards?limit=many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].is_string());
+    }
+
+    #[test]
+    fn known_params_deserialize() {
+        let uri: Uri = "/api/cards?sort=price&order=desc&mode=fts&limit=10"
+            .parse()
+            .unwrap();
+        let Query(params) = Query::<CardQueryParams>::try_from_uri(&uri).unwrap();
+        assert_eq!(params.limit, 10);
+        assert_eq!(params.sort, Some(CardSort::Price));
+        assert_eq!(params.order, SortOrder::Desc);
+        assert_eq!(params.mode, CardSearchType::FullText);
+    }
+}
@@ -0,0 +1,16 @@
+use crate::db::{get_dataset, DbConnection};
+use axum::{extract::State, response::IntoResponse, Json};
+use reqwest::StatusCode;
+use std::sync::Arc;
+
+pub async fn get_dataset_info(State(db): State<Arc<DbConnection>>) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_dataset(&conn) {
+        Ok(dataset) => (StatusCode::OK, Json(Some(dataset))),
+        Err(e) => {
+            println!("Error getting dataset info: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(None))
+        }
+    }
+}
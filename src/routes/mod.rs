@@ -1,5 +1,41 @@
 mod cards;
+mod dataset;
+mod rulings;
+mod sets;
 mod vectors;
 
-pub use cards::get_cards;
+pub use cards::{get_card_price_history, get_cards, get_related_cards, post_deck_tokens};
+pub use dataset::get_dataset_info;
+pub use rulings::{get_rulings, get_rulings_for_card};
+pub use sets::{get_all_sets, get_cards_in_set, get_set_by_code};
 pub use vectors::*;
+
+use crate::{config::Settings, db::DbConnection, embedings::Embedder};
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+/// State shared by every handler. Handlers extract only the parts they use.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Arc<DbConnection>,
+    pub settings: Arc<Settings>,
+    pub embedder: Arc<Embedder>,
+}
+
+impl FromRef<AppState> for Arc<DbConnection> {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Embedder> {
+    fn from_ref(state: &AppState) -> Self {
+        state.embedder.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Settings> {
+    fn from_ref(state: &AppState) -> Self {
+        state.settings.clone()
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    db::{
+        rulings::{get_card_rulings, search_rulings},
+        vectors::IncompleteEmbeddingIndex,
+        DbConnection,
+    },
+    embedings::{Embedder, ModelMismatch},
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::cards::{default_limit, default_page, error_response, MAX_LIMIT};
+
+#[derive(Deserialize)]
+pub struct RulingQueryParams {
+    #[serde(default = "default_page")]
+    page: u32,
+    /// Rulings per page, at most `MAX_LIMIT`
+    #[serde(default = "default_limit")]
+    limit: u32,
+    search: String,
+}
+
+pub async fn get_rulings(
+    State(db): State<Arc<DbConnection>>,
+    State(embedder): State<Arc<Embedder>>,
+    params: Query<RulingQueryParams>,
+) -> Response {
+    if !(1..=MAX_LIMIT).contains(&params.limit) {
+        let message = format!("limit must be between 1 and {}", MAX_LIMIT);
+        return error_response(StatusCode::BAD_REQUEST, message);
+    }
+    // Embedded before taking the lock so other requests can use the db meanwhile
+    let query_embedding = match embedder.embed_query(&params.search).await {
+        Ok(query_embedding) => query_embedding,
+        Err(e) => {
+            println!("Error embedding rulings search: {:?}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to embed the search",
+            );
+        }
+    };
+    let conn = db.0.lock().await;
+
+    match search_rulings(&conn, &query_embedding, params.page, params.limit) {
+        Ok(rulings) => (StatusCode::OK, Json(rulings)).into_response(),
+        Err(e) if e.is::<ModelMismatch>() || e.is::<IncompleteEmbeddingIndex>() => {
+            error_response(StatusCode::CONFLICT, e)
+        }
+        Err(e) => {
+            println!("Error searching rulings: {:?}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to search rulings",
+            )
+        }
+    }
+}
+
+pub async fn get_rulings_for_card(
+    State(db): State<Arc<DbConnection>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_card_rulings(&conn, &id) {
+        Ok(Some(rulings)) => (StatusCode::OK, Json(rulings)),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(vec![])),
+        Err(e) => {
+            println!("Error finding card rulings: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::db::{get_set, get_set_cards, get_sets, Card, DbConnection};
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::cards::{default_limit, default_page, error_response, MAX_LIMIT};
+
+#[derive(Deserialize)]
+pub struct SetCardsQueryParams {
+    #[serde(default = "default_page")]
+    page: u32,
+    /// Cards per page, at most `MAX_LIMIT`
+    #[serde(default = "default_limit")]
+    limit: u32,
+}
+
+pub async fn get_all_sets(State(db): State<Arc<DbConnection>>) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_sets(&conn) {
+        Ok(sets) => (StatusCode::OK, Json(sets)),
+        Err(e) => {
+            println!("Error finding sets: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+        }
+    }
+}
+
+pub async fn get_set_by_code(
+    State(db): State<Arc<DbConnection>>,
+    Path(code): Path<String>,
+) -> impl IntoResponse {
+    let conn = db.0.lock().await;
+
+    match get_set(&conn, &code) {
+        Ok(Some(set)) => (StatusCode::OK, Json(Some(set))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(None)),
+        Err(e) => {
+            println!("Error finding set: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(None))
+        }
+    }
+}
+
+pub async fn get_cards_in_set(
+    State(db): State<Arc<DbConnection>>,
+    Path(code): Path<String>,
+    params: Query<SetCardsQueryParams>,
+) -> Response {
+    if !(1..=MAX_LIMIT).contains(&params.limit) {
+        let message = format!("limit must be between 1 and {}", MAX_LIMIT);
+        return error_response(StatusCode::BAD_REQUEST, message);
+    }
+    let conn = db.0.lock().await;
+
+    match get_set(&conn, &code) {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(Vec::<Card>::new())).into_response(),
+        Err(e) => {
+            println!("Error finding set: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::<Card>::new())).into_response();
+        }
+    }
+
+    match get_set_cards(&conn, &code, params.page, params.limit) {
+        Ok(cards) => (StatusCode::OK, Json(cards)).into_response(),
+        Err(e) => {
+            println!("Error finding set cards: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::<Card>::new())).into_response()
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use crate::{
-    db::DbConnection,
-    embedings::{init, string_to_embedding},
+    db::{
+        vectors::{check_embedding_index, VecTable},
+        DbConnection,
+    },
+    embedings::Embedder,
 };
 use anyhow::Result;
 use axum::{extract::State, response::IntoResponse, Json};
@@ -28,20 +31,10 @@ pub async fn get_vector_version(State(db): State<Arc<DbConnection>>) -> impl Int
 
 pub async fn get_card_vec_info(
     State(db): State<Arc<DbConnection>>,
+    State(embedder): State<Arc<Embedder>>,
 ) -> Result<(StatusCode, impl IntoResponse), (StatusCode, impl IntoResponse)> {
-    let conn = db.0.lock().await;
-
-    let model = match init() {
-        Ok(r) => r,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(format!("Failed to init fastembed model. \n\n {}", e)),
-            ))
-        }
-    };
-    let search = match string_to_embedding("flying hexproof", &model) {
-        Ok(r) => r,
+    let search = match embedder.embed_query("flying hexproof").await {
+        Ok(r) => r.embedding,
         Err(e) => {
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -50,6 +43,12 @@ pub async fn get_card_vec_info(
         }
     };
 
+    let conn = db.0.lock().await;
+
+    if let Err(e) = check_embedding_index(&conn, &embedder.model_name, VecTable::Cards) {
+        return Err((StatusCode::CONFLICT, Json(e.to_string())));
+    }
+
     let mut stmt = match conn.prepare(&format!(
         "
         SELECT cv.rowid, cv.distance, c.name, c.oracle_text
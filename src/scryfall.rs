@@ -0,0 +1,347 @@
+use anyhow::Result;
+use indicatif::ProgressBar;
+use serde::de::{DeserializeOwned, Error as _, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer as _, Serialize};
+use std::{collections::BTreeMap, fmt, fs::File, io::BufReader, marker::PhantomData};
+
+/// The scryfall bulk data files that contain card objects.
+///
+/// See <https://scryfall.com/docs/api/bulk-data>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkType {
+    /// One card per oracle id
+    OracleCards,
+    /// One card per unique artwork
+    UniqueArtwork,
+    /// Every card in english, or the printed language if there is no english print
+    DefaultCards,
+    /// Every card in every language
+    AllCards,
+}
+
+impl BulkType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BulkType::OracleCards => "oracle_cards",
+            BulkType::UniqueArtwork => "unique_artwork",
+            BulkType::DefaultCards => "default_cards",
+            BulkType::AllCards => "all_cards",
+        }
+    }
+
+    /// Where the downloaded bulk file is expected when no path is given
+    pub fn default_path(&self) -> String {
+        format!("./data/scryfall-{}.json", self.as_str().replace('_', "-"))
+    }
+}
+
+impl std::str::FromStr for BulkType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "oracle_cards" => Ok(BulkType::OracleCards),
+            "unique_artwork" => Ok(BulkType::UniqueArtwork),
+            "default_cards" => Ok(BulkType::DefaultCards),
+            "all_cards" => Ok(BulkType::AllCards),
+            _ => Err(anyhow::anyhow!(
+                "Unknown bulk type {}, expected one of oracle_cards, unique_artwork, default_cards, all_cards",
+                s
+            )),
+        }
+    }
+}
+
+/// Color symbols in the order of their bits in a color mask. `C` is only used
+/// for produced mana.
+const COLOR_BITS: [char; 6] = ['W', 'U', 'B', 'R', 'G', 'C'];
+
+/// Packs color symbols, e.g. `["W", "U"]`, into a bitmask so color filters
+/// become bitwise comparisons
+pub fn color_mask<S: AsRef<str>>(colors: &[S]) -> i64 {
+    colors
+        .iter()
+        .filter_map(|c| {
+            let c = c.as_ref().chars().next()?;
+            COLOR_BITS.iter().position(|b| *b == c)
+        })
+        .fold(0, |mask, bit| mask | 1 << bit)
+}
+
+/// Parses colors written like `wub` into a color mask
+pub fn parse_color_mask(colors: &str) -> Result<i64> {
+    colors.chars().try_fold(0, |mask, c| {
+        match COLOR_BITS.iter().position(|b| *b == c.to_ascii_uppercase()) {
+            Some(bit) => Ok(mask | 1 << bit),
+            None => Err(anyhow::anyhow!(
+                "Unknown color {}, expected some of wubrgc",
+                c
+            )),
+        }
+    })
+}
+
+/// Parses the colors a card has, written like `wub`. Cards have no `C` color,
+/// so `c` or `colorless` means no colors at all rather than the produced mana bit.
+pub fn parse_card_colors(colors: &str) -> Result<i64> {
+    if colors.eq_ignore_ascii_case("c") || colors.eq_ignore_ascii_case("colorless") {
+        return Ok(0);
+    }
+    let mask = parse_color_mask(colors)?;
+    if mask & color_mask(&["C"]) != 0 {
+        return Err(anyhow::anyhow!(
+            "Colorless can't be combined with other colors in {}",
+            colors
+        ));
+    }
+    Ok(mask)
+}
+
+pub fn mask_to_colors(mask: i64) -> Vec<String> {
+    COLOR_BITS
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| mask & 1 << bit != 0)
+        .map(|(_, c)| c.to_string())
+        .collect()
+}
+
+/// A card object from the scryfall bulk data files.
+///
+/// See <https://scryfall.com/docs/api/cards>
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Card {
+    pub id: String,
+    // Reversible cards keep their oracle_id on each face instead
+    pub oracle_id: Option<String>,
+    pub name: String,
+    pub lang: String,
+    pub released_at: String,
+    pub layout: String,
+    pub mana_cost: Option<String>,
+    pub cmc: Option<f64>,
+    pub type_line: Option<String>,
+    pub oracle_text: Option<String>,
+    pub power: Option<String>,
+    pub toughness: Option<String>,
+    pub colors: Option<Vec<String>>,
+    pub color_identity: Vec<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    pub produced_mana: Option<Vec<String>>,
+    pub rarity: String,
+    pub flavor_text: Option<String>,
+    pub artist: Option<String>,
+    pub set: String,
+    pub set_name: String,
+    pub set_type: String,
+    pub collector_number: String,
+    pub digital: bool,
+    pub image_uris: Option<ImageUris>,
+    pub card_faces: Option<Vec<CardFace>>,
+    pub legalities: Legalities,
+    pub prices: Prices,
+    pub all_parts: Option<Vec<RelatedCard>>,
+}
+
+impl Card {
+    /// The card's colors, or the colors of all its faces for multi-faced
+    /// cards that only have colors on their faces
+    pub fn all_colors(&self) -> Option<Vec<String>> {
+        if self.colors.is_some() {
+            return self.colors.clone();
+        }
+        let faces = self.card_faces.as_ref()?;
+        let mut colors: Vec<String> = faces
+            .iter()
+            .flat_map(|f| f.colors.iter().flatten().cloned())
+            .collect();
+        colors.sort();
+        colors.dedup();
+        Some(colors)
+    }
+}
+
+/// One face of a multi-faced card (transform, modal dfc, flip, split, adventure...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardFace {
+    pub name: String,
+    pub oracle_id: Option<String>,
+    pub mana_cost: Option<String>,
+    pub cmc: Option<f64>,
+    pub type_line: Option<String>,
+    pub oracle_text: Option<String>,
+    pub power: Option<String>,
+    pub toughness: Option<String>,
+    pub colors: Option<Vec<String>>,
+    pub flavor_text: Option<String>,
+    pub artist: Option<String>,
+    pub image_uris: Option<ImageUris>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUris {
+    pub small: String,
+    pub normal: String,
+    pub large: String,
+    pub png: String,
+    pub art_crop: String,
+    pub border_crop: String,
+}
+
+/// Legality of a card keyed by format name, e.g. `modern -> legal`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Legalities(pub BTreeMap<String, Legality>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Legality {
+    Legal,
+    NotLegal,
+    Restricted,
+    Banned,
+}
+
+impl Legality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Legality::Legal => "legal",
+            Legality::NotLegal => "not_legal",
+            Legality::Restricted => "restricted",
+            Legality::Banned => "banned",
+        }
+    }
+}
+
+/// Scryfall reports prices as decimal strings, or null when there is no price
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Prices {
+    pub usd: Option<String>,
+    pub usd_foil: Option<String>,
+    pub usd_etched: Option<String>,
+    pub eur: Option<String>,
+    pub eur_foil: Option<String>,
+    pub tix: Option<String>,
+}
+
+impl Prices {
+    /// Whether there is none of the prices the snapshot stores, so a card
+    /// with only an `eur_foil` price gets no empty snapshot row
+    pub fn is_empty(&self) -> bool {
+        [
+            &self.usd,
+            &self.usd_foil,
+            &self.usd_etched,
+            &self.eur,
+            &self.tix,
+        ]
+        .iter()
+        .all(|p| p.is_none())
+    }
+}
+
+/// Parses one of the decimal string prices
+pub fn parse_price(price: &Option<String>) -> Option<f64> {
+    price.as_deref().and_then(|p| p.parse().ok())
+}
+
+/// An entry of a card's `all_parts`, e.g. a token it creates or its meld partner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelatedCard {
+    pub id: String,
+    pub component: String,
+    pub name: String,
+    pub type_line: String,
+    pub uri: String,
+}
+
+/// A set object from the scryfall sets data.
+///
+/// See <https://scryfall.com/docs/api/sets>
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Set {
+    pub code: String,
+    pub name: String,
+    pub set_type: String,
+    pub released_at: Option<String>,
+    pub parent_set_code: Option<String>,
+    pub block: Option<String>,
+    pub card_count: i64,
+    pub digital: bool,
+    pub icon_svg_uri: Option<String>,
+}
+
+/// The sets data is either the `/sets` API response, a list object wrapping
+/// the sets, or a bare array of sets
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SetList {
+    List { data: Vec<Set> },
+    Array(Vec<Set>),
+}
+
+impl SetList {
+    pub fn into_sets(self) -> Vec<Set> {
+        match self {
+            SetList::List { data } => data,
+            SetList::Array(sets) => sets,
+        }
+    }
+}
+
+/// A ruling from the scryfall rulings bulk file. Rulings apply to every
+/// print of a card, so they are keyed by oracle id.
+///
+/// See <https://scryfall.com/docs/api/rulings>
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ruling {
+    pub oracle_id: String,
+    pub source: String,
+    pub published_at: String,
+    pub comment: String,
+}
+
+/// Visits a top-level JSON array one element at a time, handing each
+/// element to `on_item` so the whole bulk file never has to be in memory.
+struct SeqVisitor<T, F> {
+    on_item: F,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T, F> Visitor<'de> for SeqVisitor<T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of scryfall objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(item) = seq.next_element::<T>()? {
+            (self.on_item)(item).map_err(A::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streams the objects in a scryfall bulk data file, advancing `progress_bar`
+/// by the number of bytes read.
+pub fn for_each_item<T, F>(path: &str, progress_bar: &ProgressBar, on_item: F) -> Result<()>
+where
+    T: DeserializeOwned,
+    F: FnMut(T) -> Result<()>,
+{
+    let file = File::open(path)?;
+    progress_bar.set_length(file.metadata()?.len());
+    let reader = BufReader::new(progress_bar.wrap_read(file));
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_seq(SeqVisitor {
+        on_item,
+        marker: PhantomData,
+    })?;
+    Ok(())
+}
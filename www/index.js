@@ -9,17 +9,24 @@ function getCardData(page = 1, limit = 10, search = '') {
   limit = limit === 10 && urlLimit ? parseInt(urlLimit) : limit;
   search = search === '' && urlSearch ? urlSearch : search;
 
-  const url = `/api/cards?page=${page}&limit=${limit}&search=${search}`;
+  const filters = new URLSearchParams({ page, limit, search });
+  if (setFilter.value) filters.set('set', setFilter.value);
+  if (rarityFilter.value) filters.set('rarity', rarityFilter.value);
+  if (cmcFilter.value) filters.set('cmc_max', cmcFilter.value);
+  if (sortSelect.value) filters.set('sort', sortSelect.value);
+
+  const url = `/api/cards?${filters.toString()}`;
   return fetch(url)
     .then(response => response.json())
     .then(data => {
-      const formattedCards = data.map(card => ({
+      const formattedCards = data.items.map(card => ({
         name: card.name,
         manaCost: card.mana_cost,
         type: card.type_line,
         imageUrl: card.image_url
       }));
       renderCards(formattedCards);
+      loadMoreButton.hidden = !data.next_cursor;
       // Update the URL with the query parameters
       const queryParams = new URLSearchParams({ page, limit, search }).toString();
       history.pushState(null, null, `?${queryParams}`);
@@ -29,6 +36,27 @@ function getCardData(page = 1, limit = 10, search = '') {
     });
 }
 
+const setFilter = document.getElementById('set-filter');
+const rarityFilter = document.getElementById('rarity-filter');
+const cmcFilter = document.getElementById('cmc-filter');
+const sortSelect = document.getElementById('sort');
+
+function loadSets() {
+  return fetch('/api/sets')
+    .then(response => response.json())
+    .then(sets => {
+      sets.forEach(set => {
+        const option = document.createElement('option');
+        option.value = set.code;
+        option.textContent = set.name;
+        setFilter.appendChild(option);
+      });
+    })
+    .catch(error => {
+      console.error('Error fetching sets:', error);
+    });
+}
+
 const cardGrid = document.getElementById('card-grid');
 const searchInput = document.getElementById('search');
 searchInput.value = urlSearch;
@@ -61,6 +89,9 @@ function searchCards() {
 }
 
 searchInput.addEventListener('input', searchCards);
+[setFilter, rarityFilter, cmcFilter, sortSelect].forEach(filter => {
+  filter.addEventListener('change', searchCards);
+});
 loadMoreButton.addEventListener('click', () => {
   const queryParams = new URLSearchParams({ page: urlPage + 1, limit: urlLimit, search: urlSearch }).toString();
   history.pushState(null, null, `?${queryParams}`);
@@ -77,4 +108,5 @@ const parseUrlParams = () => {
 }
 
 // Initial render
+loadSets();
 getCardData();